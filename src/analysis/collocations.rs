@@ -0,0 +1,260 @@
+use crate::analysis::measures::ContingencyTable;
+use crate::analysis::{Scope, TokenStream, Vocabulary};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::labels::pos::PosPattern;
+use crate::marble::read::ReadState;
+use crate::marble::CorpusState;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssociationMeasure {
+    Pmi,
+    LogLikelihood,
+    TScore,
+    Dice,
+    ChiSquared,
+}
+
+#[derive(Clone, Debug)]
+pub struct CollocationOptions {
+    /// collocates are counted up to this many tokens to the right of the node
+    pub window: usize,
+    /// pairs seen fewer times than this are dropped
+    pub min_frequency: u64,
+    /// results are ranked by this measure
+    pub measure: AssociationMeasure,
+    /// keep only pairs whose node and collocate fill this two-slot pattern
+    pub pattern: Option<PosPattern>,
+    pub limit: Option<usize>,
+}
+
+impl Default for CollocationOptions {
+    fn default() -> Self {
+        Self {
+            window: 5,
+            min_frequency: 2,
+            measure: AssociationMeasure::LogLikelihood,
+            pattern: None,
+            limit: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Collocation {
+    pub node: String,
+    pub collocate: String,
+    pub frequency: u64,
+    pub pmi: f64,
+    pub log_likelihood: f64,
+    pub t_score: f64,
+    pub dice: f64,
+    pub chi_squared: f64,
+}
+
+impl Collocation {
+    pub fn score(&self, measure: AssociationMeasure) -> f64 {
+        match measure {
+            AssociationMeasure::Pmi => self.pmi,
+            AssociationMeasure::LogLikelihood => self.log_likelihood,
+            AssociationMeasure::TScore => self.t_score,
+            AssociationMeasure::Dice => self.dice,
+            AssociationMeasure::ChiSquared => self.chi_squared,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Collocations {
+    pub scope: Scope,
+    pub window: usize,
+    pub measure: AssociationMeasure,
+    pub entries: Vec<Collocation>,
+}
+
+impl Collocations {
+    pub fn to_json(&self) -> CorpusResult<String> {
+        serde_json::to_string(self).map_err(|e| CorpusError::EncodingError(e.to_string()))
+    }
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "node\tcollocate\tfrequency\tpmi\tlog_likelihood\tt_score\tdice\tchi_squared"
+        );
+        for c in self.entries.iter() {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                c.node,
+                c.collocate,
+                c.frequency,
+                c.pmi,
+                c.log_likelihood,
+                c.t_score,
+                c.dice,
+                c.chi_squared
+            );
+        }
+        out
+    }
+}
+
+/// counts every (node, collocate) pair within `options.window` tokens.
+/// marginals are taken over all pairs so the POS pattern only decides which
+/// pairs are reported, not how they are scored
+pub(crate) fn collocations(
+    vocabulary: &Vocabulary,
+    streams: &[TokenStream],
+    options: &CollocationOptions,
+) -> CorpusResult<Vec<Collocation>> {
+    if let Some(ref pattern) = options.pattern {
+        if pattern.len() != 2 {
+            return Err(CorpusError::ConfigurationError(
+                "Collocation POS patterns need exactly two slots".to_string(),
+            ));
+        }
+    }
+    let mut pairs: HashMap<(u32, u32), u64> = HashMap::new();
+    let mut nodes: HashMap<u32, u64> = HashMap::new();
+    let mut collocates: HashMap<u32, u64> = HashMap::new();
+    let mut total = 0u64;
+    for stream in streams {
+        let tokens = stream.tokens.as_slice();
+        for (i, node) in tokens.iter().enumerate() {
+            for collocate in tokens.iter().skip(i + 1).take(options.window) {
+                let key = (node.text, collocate.text);
+                total += 1;
+                *nodes.entry(node.text).or_insert(0) += 1;
+                *collocates.entry(collocate.text).or_insert(0) += 1;
+                let matched = options
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|p| p.matches(&[node.labels, collocate.labels]));
+                if matched {
+                    *pairs.entry(key).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    let mut out = pairs
+        .into_iter()
+        .filter(|(_, frequency)| *frequency >= options.min_frequency)
+        .map(|((node, collocate), frequency)| {
+            let table = ContingencyTable::from_marginals(
+                frequency,
+                nodes[&node],
+                collocates[&collocate],
+                total,
+            );
+            Collocation {
+                node: vocabulary.get(node).to_string(),
+                collocate: vocabulary.get(collocate).to_string(),
+                frequency,
+                pmi: table.pmi(),
+                log_likelihood: table.log_likelihood(),
+                t_score: table.t_score(),
+                dice: table.dice(),
+                chi_squared: table.chi_squared(),
+            }
+        })
+        .collect::<Vec<Collocation>>();
+    out.sort_by(|a, b| {
+        b.score(options.measure)
+            .total_cmp(&a.score(options.measure))
+            .then_with(|| b.frequency.cmp(&a.frequency))
+            .then_with(|| a.node.cmp(&b.node))
+            .then_with(|| a.collocate.cmp(&b.collocate))
+    });
+    if let Some(limit) = options.limit {
+        out.truncate(limit);
+    }
+    Ok(out)
+}
+
+impl CorpusState<ReadState> {
+    pub(crate) fn collocations(
+        &self,
+        scope: &Scope,
+        options: &CollocationOptions,
+    ) -> CorpusResult<Collocations> {
+        let (vocabulary, streams) = self.token_streams(scope)?;
+        Ok(Collocations {
+            scope: scope.clone(),
+            window: options.window,
+            measure: options.measure,
+            entries: collocations(&vocabulary, &streams, options)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::StreamToken;
+    use crate::labels::pos::PosLbls;
+
+    fn stream(vocabulary: &mut Vocabulary, words: &[(&str, PosLbls)]) -> TokenStream {
+        TokenStream {
+            document_id: 1,
+            tokens: words
+                .iter()
+                .enumerate()
+                .map(|(i, (w, p))| StreamToken {
+                    line: 0,
+                    position: i as u64,
+                    text: vocabulary.intern(w),
+                    labels: *p as u128,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn collocations_pos_pattern() -> CorpusResult<()> {
+        let mut vocabulary = Vocabulary::default();
+        let words = [
+            ("the", PosLbls::PosDT),
+            ("red", PosLbls::PosJJ),
+            ("house", PosLbls::PosNN),
+            ("was", PosLbls::PosVBD),
+            ("the", PosLbls::PosDT),
+            ("red", PosLbls::PosJJ),
+            ("house", PosLbls::PosNN),
+        ];
+        let streams = vec![stream(&mut vocabulary, &words)];
+        let options = CollocationOptions {
+            window: 1,
+            min_frequency: 1,
+            pattern: Some("ADJ+NOUN".parse().expect("pattern")),
+            ..CollocationOptions::default()
+        };
+        let found = collocations(&vocabulary, &streams, &options)?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].node, "red");
+        assert_eq!(found[0].collocate, "house");
+        assert_eq!(found[0].frequency, 2);
+        assert!(found[0].pmi > 0.0);
+        let unfiltered = CollocationOptions {
+            pattern: None,
+            ..options
+        };
+        assert_eq!(collocations(&vocabulary, &streams, &unfiltered)?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn collocations_bad_pattern() {
+        let options = CollocationOptions {
+            pattern: Some("ADJ".parse().expect("pattern")),
+            ..CollocationOptions::default()
+        };
+        match collocations(&Vocabulary::default(), &[], &options) {
+            Err(CorpusError::ConfigurationError(_)) => (),
+            r => panic!("bad pattern accepted: {r:?}"),
+        }
+    }
+}
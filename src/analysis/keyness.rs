@@ -0,0 +1,220 @@
+use crate::analysis::measures::ContingencyTable;
+use crate::analysis::stats::{FrequencyCounts, FrequencyKey};
+use crate::analysis::Scope;
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::read::ReadState;
+use crate::marble::CorpusState;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// normalised frequency standing in for zero when computing %DIFF
+const PERCENT_DIFF_ZERO: f64 = 1e-18;
+/// frequency standing in for zero when computing log ratio
+const LOG_RATIO_ZERO: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeynessMeasure {
+    LogLikelihood,
+    PercentDiff,
+    LogRatio,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeynessOptions {
+    pub key: FrequencyKey,
+    /// keywords are ranked by this measure, highest first
+    pub measure: KeynessMeasure,
+    /// words seen fewer times than this across both subcorpora are dropped
+    pub min_frequency: u64,
+    pub limit: Option<usize>,
+}
+
+impl Default for KeynessOptions {
+    fn default() -> Self {
+        Self {
+            key: FrequencyKey::Text,
+            measure: KeynessMeasure::LogLikelihood,
+            min_frequency: 1,
+            limit: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Keyword {
+    pub key: String,
+    pub target_frequency: u64,
+    pub reference_frequency: u64,
+    /// G², negative when the word is relatively less frequent in the target
+    pub log_likelihood: f64,
+    /// difference in normalised frequency as a percentage of the reference
+    pub percent_diff: f64,
+    /// binary log of the ratio of relative frequencies
+    pub log_ratio: f64,
+}
+
+impl Keyword {
+    pub fn score(&self, measure: KeynessMeasure) -> f64 {
+        match measure {
+            KeynessMeasure::LogLikelihood => self.log_likelihood,
+            KeynessMeasure::PercentDiff => self.percent_diff,
+            KeynessMeasure::LogRatio => self.log_ratio,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Keyness {
+    pub target: Scope,
+    pub reference: Scope,
+    pub target_tokens: u64,
+    pub reference_tokens: u64,
+    pub measure: KeynessMeasure,
+    pub entries: Vec<Keyword>,
+}
+
+impl Keyness {
+    pub fn to_json(&self) -> CorpusResult<String> {
+        serde_json::to_string(self).map_err(|e| CorpusError::EncodingError(e.to_string()))
+    }
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "key\ttarget_frequency\treference_frequency\tlog_likelihood\tpercent_diff\tlog_ratio"
+        );
+        for k in self.entries.iter() {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                k.key,
+                k.target_frequency,
+                k.reference_frequency,
+                k.log_likelihood,
+                k.percent_diff,
+                k.log_ratio
+            );
+        }
+        out
+    }
+}
+
+/// score every key seen in either subcorpus
+pub(crate) fn keywords(
+    target: &FrequencyCounts,
+    reference: &FrequencyCounts,
+    options: &KeynessOptions,
+) -> Vec<Keyword> {
+    let (c, d) = (target.tokens(), reference.tokens());
+    let keys = target
+        .iter()
+        .chain(reference.iter())
+        .map(|(key, _)| key)
+        .collect::<BTreeSet<&String>>();
+    let mut out = keys
+        .into_iter()
+        .filter_map(|key| {
+            let (a, b) = (target.get(key), reference.get(key));
+            if a + b < options.min_frequency {
+                return None;
+            }
+            let table = ContingencyTable::from_marginals(a, a + b, c, c + d);
+            let (rel_a, rel_b) = (relative(a, c), relative(b, d));
+            let sign = if rel_a < rel_b { -1.0 } else { 1.0 };
+            let rel_b_nonzero = if b == 0 { PERCENT_DIFF_ZERO } else { rel_b };
+            let log_ratio =
+                (relative_or(a, c, LOG_RATIO_ZERO) / relative_or(b, d, LOG_RATIO_ZERO)).log2();
+            Some(Keyword {
+                key: key.clone(),
+                target_frequency: a,
+                reference_frequency: b,
+                log_likelihood: sign * table.log_likelihood(),
+                percent_diff: (rel_a - rel_b) * 100.0 / rel_b_nonzero,
+                log_ratio,
+            })
+        })
+        .collect::<Vec<Keyword>>();
+    out.sort_by(|x, y| {
+        y.score(options.measure)
+            .total_cmp(&x.score(options.measure))
+            .then_with(|| x.key.cmp(&y.key))
+    });
+    if let Some(limit) = options.limit {
+        out.truncate(limit);
+    }
+    out
+}
+
+fn relative(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+fn relative_or(count: u64, total: u64, zero: f64) -> f64 {
+    let total = if total == 0 { 1.0 } else { total as f64 };
+    if count == 0 {
+        zero / total
+    } else {
+        count as f64 / total
+    }
+}
+
+impl CorpusState<ReadState> {
+    /// compare `target` against `reference`; the two may overlap
+    pub(crate) fn keyness(
+        &self,
+        target: &Scope,
+        reference: &Scope,
+        options: &KeynessOptions,
+    ) -> CorpusResult<Keyness> {
+        let t = self.frequencies(target, options.key)?;
+        let r = self.frequencies(reference, options.key)?;
+        Ok(Keyness {
+            target: target.clone(),
+            reference: reference.clone(),
+            target_tokens: t.tokens(),
+            reference_tokens: r.tokens(),
+            measure: options.measure,
+            entries: keywords(&t, &r, options),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn counts(pairs: &[(&str, u64)]) -> FrequencyCounts {
+        let mut c = FrequencyCounts::new();
+        for (k, n) in pairs {
+            c.add(k, *n);
+        }
+        c
+    }
+    #[test]
+    fn keywords_ranked() {
+        let target = counts(&[("whale", 30), ("the", 100), ("sea", 10)]);
+        let reference = counts(&[("whale", 1), ("the", 200), ("sea", 10), ("land", 40)]);
+        let found = keywords(&target, &reference, &KeynessOptions::default());
+        assert_eq!(found.first().map(|k| k.key.as_str()), Some("whale"));
+        assert_eq!(found.last().map(|k| k.key.as_str()), Some("land"));
+        let whale = &found[0];
+        assert!(whale.log_likelihood > 0.0);
+        assert!((whale.log_ratio - ((30.0 / 140.0) / (1.0 / 251.0f64)).log2()).abs() < 1e-9);
+        let land = found.last().unwrap();
+        assert!(land.log_likelihood < 0.0);
+        assert!((land.percent_diff + 100.0).abs() < 1e-9);
+    }
+    #[test]
+    fn keywords_zero_reference() {
+        let target = counts(&[("a", 2)]);
+        let found = keywords(&target, &FrequencyCounts::new(), &KeynessOptions::default());
+        assert_eq!(found.len(), 1);
+        assert!(found[0].percent_diff.is_finite());
+        assert!(found[0].log_ratio.is_finite());
+    }
+}
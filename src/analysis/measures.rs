@@ -0,0 +1,134 @@
+/// 2x2 contingency table for a pair of events x and y
+///
+/// |       | y     | ¬y    |
+/// |-------|-------|-------|
+/// | x     | o11   | o12   |
+/// | ¬x    | o21   | o22   |
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContingencyTable {
+    pub o11: f64,
+    pub o12: f64,
+    pub o21: f64,
+    pub o22: f64,
+}
+
+impl ContingencyTable {
+    /// build from the joint frequency, both marginals and the sample size
+    pub fn from_marginals(joint: u64, x: u64, y: u64, n: u64) -> Self {
+        let (joint, x, y, n) = (joint as f64, x as f64, y as f64, n as f64);
+        Self {
+            o11: joint,
+            o12: x - joint,
+            o21: y - joint,
+            o22: n - x - y + joint,
+        }
+    }
+    pub fn n(&self) -> f64 {
+        self.o11 + self.o12 + self.o21 + self.o22
+    }
+    fn r1(&self) -> f64 {
+        self.o11 + self.o12
+    }
+    fn r2(&self) -> f64 {
+        self.o21 + self.o22
+    }
+    fn c1(&self) -> f64 {
+        self.o11 + self.o21
+    }
+    fn c2(&self) -> f64 {
+        self.o12 + self.o22
+    }
+    /// expected frequencies under independence, in the same order as the
+    /// observed cells
+    pub fn expected(&self) -> [f64; 4] {
+        let n = self.n();
+        if n == 0.0 {
+            return [0.0; 4];
+        }
+        [
+            self.r1() * self.c1() / n,
+            self.r1() * self.c2() / n,
+            self.r2() * self.c1() / n,
+            self.r2() * self.c2() / n,
+        ]
+    }
+    /// pointwise mutual information, in bits
+    pub fn pmi(&self) -> f64 {
+        let e11 = self.expected()[0];
+        if self.o11 == 0.0 || e11 == 0.0 {
+            0.0
+        } else {
+            (self.o11 / e11).log2()
+        }
+    }
+    /// log-likelihood ratio (G²)
+    pub fn log_likelihood(&self) -> f64 {
+        let observed = [self.o11, self.o12, self.o21, self.o22];
+        2.0 * observed
+            .iter()
+            .zip(self.expected().iter())
+            .filter(|(o, e)| **o > 0.0 && **e > 0.0)
+            .map(|(o, e)| o * (o / e).ln())
+            .sum::<f64>()
+    }
+    pub fn t_score(&self) -> f64 {
+        if self.o11 == 0.0 {
+            0.0
+        } else {
+            (self.o11 - self.expected()[0]) / self.o11.sqrt()
+        }
+    }
+    pub fn dice(&self) -> f64 {
+        let d = self.r1() + self.c1();
+        if d == 0.0 {
+            0.0
+        } else {
+            2.0 * self.o11 / d
+        }
+    }
+    /// Pearson's chi-squared, without continuity correction
+    pub fn chi_squared(&self) -> f64 {
+        let d = self.r1() * self.r2() * self.c1() * self.c2();
+        if d == 0.0 {
+            0.0
+        } else {
+            self.n() * (self.o11 * self.o22 - self.o12 * self.o21).powi(2) / d
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+    #[test]
+    fn contingency_table_measures() {
+        let t = ContingencyTable::from_marginals(10, 20, 40, 200);
+        assert_eq!(t.n(), 200.0);
+        assert_eq!(t.expected(), [4.0, 16.0, 36.0, 144.0]);
+        assert!(close(t.pmi(), (10.0f64 / 4.0).log2()));
+        assert!(close(t.t_score(), 6.0 / 10.0f64.sqrt()));
+        assert!(close(t.dice(), 20.0 / 60.0));
+        assert!(close(
+            t.chi_squared(),
+            200.0 * (10.0 * 150.0 - 10.0 * 30.0f64).powi(2) / (20.0 * 180.0 * 40.0 * 160.0)
+        ));
+        let g2 = 2.0
+            * (10.0 * (10.0f64 / 4.0).ln()
+                + 10.0 * (10.0f64 / 16.0).ln()
+                + 30.0 * (30.0f64 / 36.0).ln()
+                + 150.0 * (150.0f64 / 144.0).ln());
+        assert!(close(t.log_likelihood(), g2));
+    }
+    #[test]
+    fn contingency_table_empty() {
+        let t = ContingencyTable::from_marginals(0, 0, 0, 0);
+        assert_eq!(t.pmi(), 0.0);
+        assert_eq!(t.log_likelihood(), 0.0);
+        assert_eq!(t.t_score(), 0.0);
+        assert_eq!(t.dice(), 0.0);
+        assert_eq!(t.chi_squared(), 0.0);
+    }
+}
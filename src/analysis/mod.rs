@@ -0,0 +1,235 @@
+pub(crate) mod collocations;
+pub(crate) mod keyness;
+pub(crate) mod measures;
+pub(crate) mod ngrams;
+pub(crate) mod stats;
+pub(crate) mod subcorpus;
+
+use crate::entities::token_columns::TokenColumns;
+use crate::entities::{CorpusEntity, Document, HasId, StringRef};
+use crate::errors::CorpusResult;
+use crate::marble::read::ReadState;
+use crate::marble::CorpusState;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// the part of the corpus an analysis runs over
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum Scope {
+    Corpus,
+    Collection(u128),
+    Author(u128),
+    Document(u128),
+    /// documents dated between the two bounds, inclusive
+    Dates(DateTime<Utc>, DateTime<Utc>),
+    /// a `Subcorpus` saved under this name
+    Subcorpus(String),
+}
+
+impl Scope {
+    /// whether `document` belongs to this scope. named subcorpora need the
+    /// corpus to resolve and never match here; see `ScopeFilter`
+    pub(crate) fn selects(&self, document: &Document) -> bool {
+        match self {
+            Self::Corpus => true,
+            Self::Collection(id) => document.collection_id() == *id,
+            Self::Author(id) => document.author_id() == *id,
+            Self::Document(id) => document.id() == *id,
+            Self::Dates(from, to) => {
+                (from.timestamp()..=to.timestamp()).contains(&(document.date() as i64))
+            }
+            Self::Subcorpus(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Corpus => write!(f, "corpus"),
+            Self::Collection(id) => write!(f, "collection:{id}"),
+            Self::Author(id) => write!(f, "author:{id}"),
+            Self::Document(id) => write!(f, "document:{id}"),
+            Self::Dates(from, to) => write!(f, "dates:{}..{}", from.timestamp(), to.timestamp()),
+            Self::Subcorpus(name) => write!(f, "subcorpus:{name}"),
+        }
+    }
+}
+
+/// `Scope` resolved against the corpus so tokens can be tested directly
+#[derive(Debug)]
+pub(crate) struct ScopeFilter {
+    scope: Scope,
+    documents: Option<HashSet<u128>>,
+}
+
+impl ScopeFilter {
+    pub(crate) fn new(corpus: &CorpusState<ReadState>, scope: &Scope) -> CorpusResult<Self> {
+        let documents = match scope {
+            Scope::Corpus | Scope::Author(_) | Scope::Document(_) => None,
+            Scope::Subcorpus(name) => {
+                let subcorpus = corpus.subcorpus(name)?;
+                Some(corpus.select_documents(|page_id, d| {
+                    subcorpus.selects(d, || {
+                        corpus.with_strings(page_id, |strings| strings.get_string(&d.title()))
+                    })
+                })?)
+            }
+            Scope::Collection(_) | Scope::Dates(_, _) => {
+                Some(corpus.select_documents(|_, d| Ok(scope.selects(d)))?)
+            }
+        };
+        Ok(Self {
+            scope: scope.clone(),
+            documents,
+        })
+    }
+    /// whether a token of `document_id` by `author_id` is in scope
+    pub(crate) fn matches(&self, document_id: u128, author_id: u128) -> bool {
+        match self.scope {
+            Scope::Corpus => true,
+            Scope::Author(id) => author_id == id,
+            _ => self.selects_document(document_id),
+        }
+    }
+    fn selects_document(&self, document_id: u128) -> bool {
+        match self.scope {
+            Scope::Document(id) => document_id == id,
+            _ => self
+                .documents
+                .as_ref()
+                .is_some_and(|ds| ds.contains(&document_id)),
+        }
+    }
+    /// which of the tokens in `columns` match, or `None` if they all do.
+    /// decodes at most the one id column the scope needs
+    pub(crate) fn select(&self, columns: &TokenColumns) -> CorpusResult<Option<Vec<bool>>> {
+        Ok(match self.scope {
+            Scope::Corpus => None,
+            Scope::Author(author_id) => Some(
+                columns
+                    .author_ids()?
+                    .into_iter()
+                    .map(|id| id == author_id)
+                    .collect(),
+            ),
+            _ => Some(
+                columns
+                    .document_ids()?
+                    .into_iter()
+                    .map(|id| self.selects_document(id))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// maps token text to small integer ids so token streams don't hold a
+/// `String` per token
+#[derive(Debug, Default)]
+pub(crate) struct Vocabulary {
+    ids: HashMap<String, u32>,
+    strings: Vec<String>,
+}
+
+impl Vocabulary {
+    /// `s`'s id, copying it only the first time it's seen
+    pub(crate) fn intern(&mut self, s: &str) -> u32 {
+        if let Some(id) = self.ids.get(s) {
+            *id
+        } else {
+            let id = self.strings.len() as u32;
+            self.ids.insert(s.to_string(), id);
+            self.strings.push(s.to_string());
+            id
+        }
+    }
+    pub(crate) fn get(&self, id: u32) -> &str {
+        self.strings[id as usize].as_str()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StreamToken {
+    pub line: u64,
+    pub position: u64,
+    pub text: u32,
+    pub labels: u128,
+}
+
+/// a document's tokens in `position` order
+#[derive(Debug)]
+pub(crate) struct TokenStream {
+    pub document_id: u128,
+    pub tokens: Vec<StreamToken>,
+}
+
+impl CorpusState<ReadState> {
+    /// ids of the documents for which `f` holds
+    pub(crate) fn select_documents<F>(&self, mut f: F) -> CorpusResult<HashSet<u128>>
+    where
+        F: FnMut(u64, &Document) -> CorpusResult<bool>,
+    {
+        let mut documents = HashSet::new();
+        self.scan(|page_id, page| {
+            for entity in page.0.values() {
+                if let CorpusEntity::Document(d) = entity {
+                    if f(page_id, d)? {
+                        documents.insert(d.id());
+                    }
+                }
+            }
+            Ok(())
+        })?;
+        Ok(documents)
+    }
+    /// every document in `scope` as a stream of tokens in `position` order
+    pub(crate) fn token_streams(
+        &self,
+        scope: &Scope,
+    ) -> CorpusResult<(Vocabulary, Vec<TokenStream>)> {
+        let filter = ScopeFilter::new(self, scope)?;
+        let mut vocabulary = Vocabulary::default();
+        let mut documents: BTreeMap<u128, Vec<StreamToken>> = BTreeMap::new();
+        for page_id in self.page_ids()? {
+            self.with_tokens(page_id, |records, strings| {
+                let mut texts: HashMap<StringRef, u32> = HashMap::new();
+                for token in records.iter() {
+                    if !filter.matches(token.document_id(), token.author_id()) {
+                        continue;
+                    }
+                    let text = match texts.entry(token.text()) {
+                        Entry::Occupied(e) => *e.get(),
+                        Entry::Vacant(e) => {
+                            *e.insert(vocabulary.intern(strings.get_str(&token.text())?))
+                        }
+                    };
+                    documents
+                        .entry(token.document_id())
+                        .or_default()
+                        .push(StreamToken {
+                            line: token.line(),
+                            position: token.position(),
+                            text,
+                            labels: token.labels(),
+                        });
+                }
+                Ok(())
+            })?;
+        }
+        let streams = documents
+            .into_iter()
+            .map(|(document_id, mut tokens)| {
+                tokens.sort_by_key(|t| t.position);
+                TokenStream {
+                    document_id,
+                    tokens,
+                }
+            })
+            .collect();
+        Ok((vocabulary, streams))
+    }
+}
@@ -0,0 +1,313 @@
+use crate::analysis::stats::FrequencyKey;
+use crate::analysis::{Scope, StreamToken, TokenStream, Vocabulary};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::labels::pos::PosLabels;
+use crate::marble::read::ReadState;
+use crate::marble::write::WriteState;
+use crate::marble::CorpusState;
+use minicbor::{Decode, Encode};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NgramOptions {
+    pub n: usize,
+    /// build n-grams from `Token.text` or from POS tags
+    pub key: FrequencyKey,
+    /// don't let n-grams cross a change in `Token.line`
+    pub respect_lines: bool,
+    /// n-grams seen fewer times than this are dropped
+    pub min_frequency: u64,
+}
+
+impl Default for NgramOptions {
+    fn default() -> Self {
+        Self {
+            n: 2,
+            key: FrequencyKey::Text,
+            respect_lines: true,
+            min_frequency: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Decode, Deserialize, Encode, PartialEq, Serialize)]
+pub struct NgramEntry {
+    #[n(0)]
+    pub gram: Vec<String>,
+    #[n(1)]
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Ngrams {
+    pub scope: Scope,
+    pub n: usize,
+    pub key: FrequencyKey,
+    pub respect_lines: bool,
+    pub min_frequency: u64,
+    pub entries: Vec<NgramEntry>,
+    /// the generation counted at. counts can't be saved once the corpus
+    /// has been written since
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+impl Ngrams {
+    /// name the counts are saved under; one artifact per scope and options
+    pub fn artifact_name(scope: &Scope, options: &NgramOptions) -> String {
+        let key = match options.key {
+            FrequencyKey::Text => "text",
+            FrequencyKey::Pos => "pos",
+        };
+        format!(
+            "ngrams/{scope}/{}/{key}/{}/{}",
+            options.n, options.respect_lines, options.min_frequency
+        )
+    }
+    pub fn options(&self) -> NgramOptions {
+        NgramOptions {
+            n: self.n,
+            key: self.key,
+            respect_lines: self.respect_lines,
+            min_frequency: self.min_frequency,
+        }
+    }
+    pub fn to_json(&self) -> CorpusResult<String> {
+        serde_json::to_string(self).map_err(|e| CorpusError::EncodingError(e.to_string()))
+    }
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "ngram\tcount");
+        for entry in self.entries.iter() {
+            let _ = writeln!(out, "{}\t{}", entry.gram.join(" "), entry.count);
+        }
+        out
+    }
+}
+
+/// count n-grams over each stream. with `respect_lines` a stream is first
+/// cut wherever `line` changes
+pub(crate) fn ngrams(
+    vocabulary: &Vocabulary,
+    streams: &[TokenStream],
+    options: &NgramOptions,
+) -> CorpusResult<Vec<NgramEntry>> {
+    if options.n == 0 {
+        return Err(CorpusError::ConfigurationError(
+            "n-grams need n of at least 1".to_string(),
+        ));
+    }
+    let pos = PosLabels {};
+    let mut tags = Vocabulary::default();
+    let mut key = |t: &StreamToken| match options.key {
+        FrequencyKey::Text => t.text,
+        FrequencyKey::Pos => tags.intern(pos.tag(t.labels).name()),
+    };
+    let mut counts: HashMap<Vec<u32>, u64> = HashMap::new();
+    for stream in streams {
+        let segments: Vec<&[StreamToken]> = if options.respect_lines {
+            stream.tokens.chunk_by(|a, b| a.line == b.line).collect()
+        } else {
+            vec![stream.tokens.as_slice()]
+        };
+        for segment in segments {
+            for window in segment.windows(options.n) {
+                let gram = window.iter().map(&mut key).collect::<Vec<u32>>();
+                *counts.entry(gram).or_insert(0) += 1;
+            }
+        }
+    }
+    let lookup = match options.key {
+        FrequencyKey::Text => vocabulary,
+        FrequencyKey::Pos => &tags,
+    };
+    let mut entries = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= options.min_frequency)
+        .map(|(gram, count)| NgramEntry {
+            gram: gram.iter().map(|id| lookup.get(*id).to_string()).collect(),
+            count,
+        })
+        .collect::<Vec<NgramEntry>>();
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.gram.cmp(&b.gram)));
+    Ok(entries)
+}
+
+impl CorpusState<ReadState> {
+    pub(crate) fn ngrams(&self, scope: &Scope, options: &NgramOptions) -> CorpusResult<Ngrams> {
+        let (vocabulary, streams) = self.token_streams(scope)?;
+        Ok(Ngrams {
+            scope: scope.clone(),
+            n: options.n,
+            key: options.key,
+            respect_lines: options.respect_lines,
+            min_frequency: options.min_frequency,
+            entries: ngrams(&vocabulary, &streams, options)?,
+            generation: self.generation()?,
+        })
+    }
+    /// previously saved counts, if the corpus hasn't been written since
+    pub(crate) fn load_ngrams(
+        &self,
+        scope: &Scope,
+        options: &NgramOptions,
+    ) -> CorpusResult<Option<Ngrams>> {
+        if let Some((raw, generation)) =
+            self.read_artifact(&Ngrams::artifact_name(scope, options))?
+        {
+            let entries = minicbor::decode::<Vec<NgramEntry>>(&raw)
+                .map_err(|_| CorpusError::DecodingError("loading n-grams".to_string()))?;
+            Ok(Some(Ngrams {
+                scope: scope.clone(),
+                n: options.n,
+                key: options.key,
+                respect_lines: options.respect_lines,
+                min_frequency: options.min_frequency,
+                entries,
+                generation,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl CorpusState<WriteState> {
+    /// fails with `StaleSnapshotError` if entities were written after the
+    /// counts were made
+    pub(crate) fn save_ngrams(&self, ngrams: &Ngrams) -> CorpusResult<()> {
+        let mut bytes = Vec::new();
+        minicbor::encode::<&Vec<NgramEntry>, &mut Vec<u8>>(&ngrams.entries, bytes.as_mut())
+            .map_err(|_| CorpusError::EncodingError("n-grams".to_string()))?;
+        self.write_artifact(
+            &Ngrams::artifact_name(&ngrams.scope, &ngrams.options()),
+            bytes,
+            ngrams.generation,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::token::HydratedToken;
+    use crate::entities::HydratedEntity;
+    use crate::labels::pos::PosLbls;
+    use crate::marble::{_test_config, _test_document, CorpusWrite};
+
+    fn stream(vocabulary: &mut Vocabulary, lines: &[&[(&str, PosLbls)]]) -> TokenStream {
+        let mut tokens = Vec::new();
+        for (line, words) in lines.iter().enumerate() {
+            for (w, p) in words.iter() {
+                tokens.push(StreamToken {
+                    line: line as u64,
+                    position: tokens.len() as u64,
+                    text: vocabulary.intern(w),
+                    labels: *p as u128,
+                });
+            }
+        }
+        TokenStream {
+            document_id: 1,
+            tokens,
+        }
+    }
+
+    #[test]
+    fn ngrams_respect_lines() -> CorpusResult<()> {
+        let mut vocabulary = Vocabulary::default();
+        let streams = vec![stream(
+            &mut vocabulary,
+            &[
+                &[("a", PosLbls::PosDT), ("cat", PosLbls::PosNN)],
+                &[("a", PosLbls::PosDT), ("cat", PosLbls::PosNN)],
+            ],
+        )];
+        let options = NgramOptions::default();
+        let found = ngrams(&vocabulary, &streams, &options)?;
+        assert_eq!(
+            found,
+            vec![NgramEntry {
+                gram: vec!["a".to_string(), "cat".to_string()],
+                count: 2
+            }]
+        );
+        let crossing = NgramOptions {
+            respect_lines: false,
+            ..options.clone()
+        };
+        assert_eq!(ngrams(&vocabulary, &streams, &crossing)?.len(), 2);
+        let frequent = NgramOptions {
+            respect_lines: false,
+            min_frequency: 2,
+            ..options
+        };
+        assert_eq!(ngrams(&vocabulary, &streams, &frequent)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn ngrams_pos() -> CorpusResult<()> {
+        let mut vocabulary = Vocabulary::default();
+        let streams = vec![stream(
+            &mut vocabulary,
+            &[&[
+                ("the", PosLbls::PosDT),
+                ("old", PosLbls::PosJJ),
+                ("cat", PosLbls::PosNN),
+            ]],
+        )];
+        let options = NgramOptions {
+            n: 3,
+            key: FrequencyKey::Pos,
+            ..NgramOptions::default()
+        };
+        let found = ngrams(&vocabulary, &streams, &options)?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].gram, vec!["DT", "JJ", "NN"]);
+        Ok(())
+    }
+
+    #[test]
+    fn ngrams_saved_until_next_write() -> CorpusResult<()> {
+        let config = _test_config("ngrams");
+        let token = |id: u128, w: &str| {
+            HydratedEntity::Token(HydratedToken::new(id, 1, 1, 0, id as u64, w.to_string(), 0))
+        };
+        let options = NgramOptions::default();
+        let mut objs = _test_document(1, 1, 1);
+        objs.extend([token(1, "a"), token(2, "cat")]);
+        CorpusState::<WriteState>::new(config.clone())?.write_objs(objs)?;
+        let reader = CorpusState::<ReadState>::new(config.clone())?;
+        let counted = reader.ngrams(&Scope::Corpus, &options)?;
+        assert!(reader.load_ngrams(&Scope::Corpus, &options)?.is_none());
+        drop(reader);
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.save_ngrams(&counted)?;
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config.clone())?;
+        assert_eq!(reader.load_ngrams(&Scope::Corpus, &options)?, Some(counted));
+        drop(reader);
+        CorpusState::<WriteState>::new(config.clone())?.write_objs(vec![token(3, "sat")])?;
+        let reader = CorpusState::<ReadState>::new(config)?;
+        assert!(reader.load_ngrams(&Scope::Corpus, &options)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_ngrams_not_saved() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("ngrams-stale"))?;
+        writer.write_objs(_test_document(1, 1, 1))?;
+        let counted = writer
+            .reader()?
+            .ngrams(&Scope::Corpus, &NgramOptions::default())?;
+        writer.write_objs(_test_document(2, 1, 1))?;
+        match writer.save_ngrams(&counted) {
+            Err(CorpusError::StaleSnapshotError(_, _)) => (),
+            r => panic!("expected a stale snapshot error, got {r:?}"),
+        }
+        Ok(())
+    }
+}
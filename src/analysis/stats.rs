@@ -0,0 +1,388 @@
+use crate::analysis::{Scope, ScopeFilter};
+use crate::entities::StringRef;
+use crate::errors::{CorpusError, CorpusResult};
+use crate::labels::pos::PosLabels;
+use crate::marble::read::ReadState;
+use crate::marble::CorpusState;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// what a frequency list is keyed on
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrequencyKey {
+    /// `Token.text`
+    Text,
+    /// the POS tag in `Token.labels`
+    Pos,
+}
+
+/// raw frequency counts. built a page at a time and merged, so counts for
+/// part of the corpus can be combined without rescanning it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrequencyCounts(HashMap<String, u64>);
+
+impl FrequencyCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add(&mut self, key: &str, count: u64) {
+        if let Some(c) = self.0.get_mut(key) {
+            *c += count;
+        } else {
+            self.0.insert(key.to_string(), count);
+        }
+    }
+    pub fn merge(&mut self, other: FrequencyCounts) {
+        for (key, count) in other.0.into_iter() {
+            *self.0.entry(key).or_insert(0) += count;
+        }
+    }
+    pub fn get(&self, key: &str) -> u64 {
+        self.0.get(key).copied().unwrap_or(0)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &u64)> {
+        self.0.iter()
+    }
+    pub fn tokens(&self) -> u64 {
+        self.0.values().sum()
+    }
+    pub fn types(&self) -> u64 {
+        self.0.len() as u64
+    }
+    /// type/token ratio
+    pub fn ttr(&self) -> f64 {
+        let tokens = self.tokens();
+        if tokens == 0 {
+            0.0
+        } else {
+            self.types() as f64 / tokens as f64
+        }
+    }
+    /// keys occurring exactly once, sorted
+    pub fn hapaxes(&self) -> Vec<String> {
+        let mut hapaxes = self
+            .0
+            .iter()
+            .filter(|(_, count)| **count == 1)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+        hapaxes.sort();
+        hapaxes
+    }
+    /// the `n` most frequent keys, ties broken alphabetically
+    pub fn top(&self, n: usize) -> Vec<FrequencyEntry> {
+        let mut entries = self
+            .0
+            .iter()
+            .map(|(key, count)| FrequencyEntry {
+                key: key.clone(),
+                count: *count,
+            })
+            .collect::<Vec<FrequencyEntry>>();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        entries.truncate(n);
+        entries
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FrequencyEntry {
+    pub key: String,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CorpusStats {
+    pub scope: Scope,
+    pub key: FrequencyKey,
+    pub tokens: u64,
+    pub types: u64,
+    pub ttr: f64,
+    pub hapaxes: Vec<String>,
+    pub top: Vec<FrequencyEntry>,
+}
+
+impl CorpusStats {
+    pub fn from_counts(
+        scope: Scope,
+        key: FrequencyKey,
+        counts: &FrequencyCounts,
+        n: usize,
+    ) -> Self {
+        Self {
+            scope,
+            key,
+            tokens: counts.tokens(),
+            types: counts.types(),
+            ttr: counts.ttr(),
+            hapaxes: counts.hapaxes(),
+            top: counts.top(n),
+        }
+    }
+    pub fn to_json(&self) -> CorpusResult<String> {
+        serde_json::to_string(self).map_err(|e| CorpusError::EncodingError(e.to_string()))
+    }
+    /// summary rows followed by a blank line and the frequency list
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "metric\tvalue");
+        let _ = writeln!(out, "tokens\t{}", self.tokens);
+        let _ = writeln!(out, "types\t{}", self.types);
+        let _ = writeln!(out, "ttr\t{}", self.ttr);
+        let _ = writeln!(out, "hapaxes\t{}", self.hapaxes.len());
+        let _ = writeln!(out);
+        let _ = writeln!(out, "rank\tkey\tcount");
+        for (rank, entry) in self.top.iter().enumerate() {
+            let _ = writeln!(out, "{}\t{}\t{}", rank + 1, entry.key, entry.count);
+        }
+        out
+    }
+}
+
+impl CorpusState<ReadState> {
+    /// over the whole corpus, sums the counts stored with each page. scoped
+    /// down, or for pages without stored counts, counts tokens by their raw
+    /// key first and only resolves each distinct key once per page, rather
+    /// than hydrating every token. only the key's column and whatever the
+    /// scope needs are decoded
+    pub(crate) fn frequencies(
+        &self,
+        scope: &Scope,
+        key: FrequencyKey,
+    ) -> CorpusResult<FrequencyCounts> {
+        let filter = ScopeFilter::new(self, scope)?;
+        let mut counts = FrequencyCounts::new();
+        for page_id in self.page_ids()? {
+            if *scope == Scope::Corpus {
+                if let Some(stored) = self.page_counts(page_id)? {
+                    match key {
+                        FrequencyKey::Text => {
+                            for (text, count) in stored.texts.iter() {
+                                counts.add(text, *count);
+                            }
+                        }
+                        FrequencyKey::Pos => {
+                            let pos = PosLabels {};
+                            for (label, count) in stored.labels.iter() {
+                                counts.add(pos.tag(u128::from_be_bytes(*label)).name(), *count);
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+            self.with_token_columns(page_id, |columns, strings| {
+                let selected = filter.select(&columns)?;
+                let keep = |i: usize| selected.as_ref().is_none_or(|s| s[i]);
+                match key {
+                    FrequencyKey::Text => {
+                        let mut refs: HashMap<StringRef, u64> = HashMap::new();
+                        for (i, text) in columns.texts()?.into_iter().enumerate() {
+                            if keep(i) {
+                                *refs.entry(text).or_insert(0) += 1;
+                            }
+                        }
+                        for (string_ref, count) in refs.iter() {
+                            counts.add(strings.get_str(string_ref)?, *count);
+                        }
+                    }
+                    FrequencyKey::Pos => {
+                        let pos = PosLabels {};
+                        let mut labels: HashMap<u128, u64> = HashMap::new();
+                        for (i, label) in columns.labels()?.into_iter().enumerate() {
+                            if keep(i) {
+                                *labels.entry(label).or_insert(0) += 1;
+                            }
+                        }
+                        for (label, count) in labels.into_iter() {
+                            counts.add(pos.tag(label).name(), count);
+                        }
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(counts)
+    }
+    pub(crate) fn stats(
+        &self,
+        scope: &Scope,
+        key: FrequencyKey,
+        n: usize,
+    ) -> CorpusResult<CorpusStats> {
+        let counts = self.frequencies(scope, key)?;
+        Ok(CorpusStats::from_counts(scope.clone(), key, &counts, n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::token::HydratedToken;
+    use crate::entities::HydratedEntity;
+    use crate::marble::write::WriteState;
+    use crate::marble::{
+        _test_config, _test_document, counts_page_id, CorpusWrite, PageCounts, PageFormat,
+        TOKEN_COLUMNS_PAGE,
+    };
+    fn counts(words: &[&str]) -> FrequencyCounts {
+        let mut c = FrequencyCounts::new();
+        for w in words {
+            c.add(w, 1);
+        }
+        c
+    }
+    #[test]
+    fn frequency_counts_summary() {
+        let c = counts(&["the", "cat", "the", "dog", "the", "cat", "sat"]);
+        assert_eq!(c.tokens(), 7);
+        assert_eq!(c.types(), 4);
+        assert!((c.ttr() - 4.0 / 7.0).abs() < f64::EPSILON);
+        assert_eq!(c.hapaxes(), vec!["dog".to_string(), "sat".to_string()]);
+        let top = c.top(2);
+        assert_eq!(top[0].key, "the");
+        assert_eq!(top[0].count, 3);
+        assert_eq!(top[1].key, "cat");
+    }
+    #[test]
+    fn frequency_counts_merge() {
+        let mut a = counts(&["a", "b"]);
+        a.merge(counts(&["b", "c"]));
+        assert_eq!(a.get("a"), 1);
+        assert_eq!(a.get("b"), 2);
+        assert_eq!(a.get("c"), 1);
+        assert_eq!(a.tokens(), 4);
+    }
+    #[test]
+    fn frequency_counts_empty() {
+        let c = FrequencyCounts::new();
+        assert_eq!(c.ttr(), 0.0);
+        assert!(c.top(10).is_empty());
+    }
+    #[test]
+    fn corpus_stats_tsv() {
+        let c = counts(&["b", "a", "b"]);
+        let stats = CorpusStats::from_counts(Scope::Corpus, FrequencyKey::Text, &c, 10);
+        let tsv = stats.to_tsv();
+        assert!(tsv.contains("tokens\t3\n"));
+        assert!(tsv.ends_with("rank\tkey\tcount\n1\tb\t2\n2\ta\t1\n"));
+        let json = stats.to_json().expect("json");
+        assert!(json.contains("\"scope\":{\"type\":\"corpus\"}"));
+        let stats = CorpusStats::from_counts(Scope::Document(3), FrequencyKey::Text, &c, 10);
+        let json = stats.to_json().expect("json");
+        assert!(json.contains("\"scope\":{\"type\":\"document\",\"id\":3}"));
+    }
+    #[test]
+    fn corpus_stats_from_marble() -> CorpusResult<()> {
+        let config = _test_config("stats");
+        let words = ["the", "cat", "the", "dog", "sat"];
+        let tokens = words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| {
+                HydratedEntity::Token(HydratedToken::new(
+                    (i + 1) as u128,
+                    1,
+                    2,
+                    0,
+                    i as u64,
+                    w.to_string(),
+                    0,
+                ))
+            })
+            .collect::<Vec<HydratedEntity>>();
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(_test_document(1, 2, 9))?;
+        writer.write_objs(tokens)?;
+        drop(writer);
+        let corpus = CorpusState::<ReadState>::new(config)?;
+        let stats = corpus.stats(&Scope::Author(2), FrequencyKey::Text, 1)?;
+        assert_eq!(stats.tokens, 5);
+        assert_eq!(stats.types, 4);
+        assert_eq!(stats.top[0].key, "the");
+        assert_eq!(stats.top[0].count, 2);
+        assert_eq!(
+            corpus
+                .stats(&Scope::Author(3), FrequencyKey::Text, 1)?
+                .tokens,
+            0
+        );
+        Ok(())
+    }
+    #[test]
+    fn frequencies_from_page_counts() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("stats-counts"))?;
+        let token = |i: u128, text: &str| {
+            HydratedEntity::Token(HydratedToken::new(
+                (1 << 64) | i,
+                1,
+                2,
+                0,
+                i as u64,
+                text.to_string(),
+                0,
+            ))
+        };
+        writer.write_objs(_test_document(1, 2, 9))?;
+        writer.write_objs(vec![token(1, "the"), token(2, "cat"), token(3, "the")])?;
+        let stored =
+            PageCounts::from_bytes(&writer.read_reserved(counts_page_id(1))?.expect("counts"))?;
+        assert_eq!(stored.texts.get("the"), Some(&2));
+        // only the page written has its counts redone
+        writer.update_objs(vec![token(2, "dog")])?;
+        let reader = writer.reader()?;
+        let counts = reader.frequencies(&Scope::Corpus, FrequencyKey::Text)?;
+        assert_eq!(
+            (counts.tokens(), counts.get("dog"), counts.get("cat")),
+            (3, 1, 0)
+        );
+        // pages written before counts were kept are counted from their tokens
+        writer.write_raw(vec![(counts_page_id(1), None)])?;
+        reader.refresh()?;
+        assert_eq!(
+            reader.frequencies(&Scope::Corpus, FrequencyKey::Text)?,
+            counts
+        );
+        let pos = reader.frequencies(&Scope::Corpus, FrequencyKey::Pos)?;
+        assert_eq!(pos.tokens(), 3);
+        Ok(())
+    }
+    #[test]
+    fn frequencies_over_token_columns() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("stats-columns"))?;
+        writer.set_page_format(PageFormat::TokenColumns)?;
+        let mut objs = _test_document(1, 2, 9);
+        objs.extend(_test_document(3, 2, 9));
+        objs.extend(
+            ["the", "cat", "the", "dog"]
+                .iter()
+                .enumerate()
+                .map(|(i, w)| {
+                    HydratedEntity::Token(HydratedToken::new(
+                        (1 << 64) | (i + 1) as u128,
+                        if i < 3 { 1 } else { 3 },
+                        2,
+                        0,
+                        i as u64,
+                        w.to_string(),
+                        0,
+                    ))
+                }),
+        );
+        writer.write_objs(objs)?;
+        assert_eq!(
+            writer.read_reserved(1)?.expect("token page")[0],
+            TOKEN_COLUMNS_PAGE
+        );
+        let reader = writer.reader()?;
+        let counts = reader.frequencies(&Scope::Corpus, FrequencyKey::Text)?;
+        assert_eq!((counts.tokens(), counts.get("the")), (4, 2));
+        let counts = reader.frequencies(&Scope::Document(1), FrequencyKey::Text)?;
+        assert_eq!((counts.tokens(), counts.get("dog")), (3, 0));
+        let counts = reader.frequencies(&Scope::Document(3), FrequencyKey::Pos)?;
+        assert_eq!(counts.tokens(), 1);
+        Ok(())
+    }
+}
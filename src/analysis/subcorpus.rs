@@ -0,0 +1,285 @@
+use crate::entities::{id_to_u128, Document, Id};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::read::ReadState;
+use crate::marble::write::{drop_artifacts, WriteState};
+use crate::marble::{CorpusState, SUBCORPORA_ID};
+use chrono::{DateTime, TimeZone, Utc};
+use minicbor::{Decode, Encode};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// a named filter over documents. every non-empty criterion must match; a
+/// document matches a list of ids if it matches any of them
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Subcorpus {
+    pub name: String,
+    pub author_ids: Vec<u128>,
+    pub collection_ids: Vec<u128>,
+    /// earliest document date, inclusive
+    pub from: Option<DateTime<Utc>>,
+    /// latest document date, inclusive
+    pub to: Option<DateTime<Utc>>,
+    /// glob over document titles: `*` matches any run of characters, `?`
+    /// any single one
+    pub title_pattern: Option<String>,
+}
+
+impl Subcorpus {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+    /// `title` is only called when there is a title pattern to check
+    pub(crate) fn selects<F>(&self, document: &Document, title: F) -> CorpusResult<bool>
+    where
+        F: FnOnce() -> CorpusResult<String>,
+    {
+        let date = document.date() as i64;
+        let selected = (self.author_ids.is_empty()
+            || self.author_ids.contains(&document.author_id()))
+            && (self.collection_ids.is_empty()
+                || self.collection_ids.contains(&document.collection_id()))
+            && self.from.is_none_or(|from| date >= from.timestamp())
+            && self.to.is_none_or(|to| date <= to.timestamp());
+        if !selected {
+            return Ok(false);
+        }
+        match self.title_pattern {
+            Some(ref pattern) => Ok(glob(pattern, &title()?)),
+            None => Ok(true),
+        }
+    }
+}
+
+fn glob(pattern: &str, s: &str) -> bool {
+    let p = pattern.chars().collect::<Vec<char>>();
+    let s = s.chars().collect::<Vec<char>>();
+    let (mut pi, mut si) = (0, 0);
+    // position of the last `*` and the input position it was tried against
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((sp, ss)) = star {
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[derive(Debug, Decode, Encode)]
+struct StoredSubcorpus {
+    #[n(0)]
+    name: String,
+    #[n(1)]
+    author_ids: Vec<Id>,
+    #[n(2)]
+    collection_ids: Vec<Id>,
+    #[n(3)]
+    from: Option<i64>,
+    #[n(4)]
+    to: Option<i64>,
+    #[n(5)]
+    title_pattern: Option<String>,
+}
+
+impl From<&Subcorpus> for StoredSubcorpus {
+    fn from(s: &Subcorpus) -> Self {
+        Self {
+            name: s.name.clone(),
+            author_ids: s.author_ids.iter().map(|id| id.to_be_bytes()).collect(),
+            collection_ids: s.collection_ids.iter().map(|id| id.to_be_bytes()).collect(),
+            from: s.from.map(|d| d.timestamp()),
+            to: s.to.map(|d| d.timestamp()),
+            title_pattern: s.title_pattern.clone(),
+        }
+    }
+}
+
+impl TryFrom<StoredSubcorpus> for Subcorpus {
+    type Error = CorpusError;
+
+    fn try_from(s: StoredSubcorpus) -> CorpusResult<Self> {
+        let date = |t: i64| {
+            Utc.timestamp_opt(t, 0)
+                .earliest()
+                .ok_or(CorpusError::DecodingError(format!(
+                    "subcorpus {} date",
+                    s.name
+                )))
+        };
+        Ok(Self {
+            author_ids: s.author_ids.iter().map(|id| id_to_u128(*id)).collect(),
+            collection_ids: s.collection_ids.iter().map(|id| id_to_u128(*id)).collect(),
+            from: s.from.map(date).transpose()?,
+            to: s.to.map(date).transpose()?,
+            title_pattern: s.title_pattern.clone(),
+            name: s.name,
+        })
+    }
+}
+
+/// every saved definition, stored in a single reserved object
+#[repr(transparent)]
+#[derive(Debug, Decode, Default, Encode)]
+#[cbor(transparent)]
+struct Subcorpora(#[n(0)] BTreeMap<String, StoredSubcorpus>);
+
+impl Subcorpora {
+    fn from_bytes(raw: Option<Vec<u8>>) -> CorpusResult<Self> {
+        match raw {
+            Some(raw) => minicbor::decode::<Subcorpora>(&raw)
+                .map_err(|_| CorpusError::DecodingError("loading subcorpora".to_string())),
+            None => Ok(Self::default()),
+        }
+    }
+    fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let mut v = Vec::new();
+        minicbor::encode::<&Subcorpora, &mut Vec<u8>>(self, v.as_mut())
+            .map_err(|_| CorpusError::EncodingError("Subcorpora encoding error".to_string()))?;
+        Ok(v)
+    }
+}
+
+/// every definition in the stored subcorpora object, if there is one
+pub(crate) fn decode_subcorpora(raw: Option<Vec<u8>>) -> CorpusResult<Vec<Subcorpus>> {
+    Subcorpora::from_bytes(raw)?
+        .0
+        .into_values()
+        .map(Subcorpus::try_from)
+        .collect()
+}
+
+impl CorpusState<ReadState> {
+    pub(crate) fn subcorpora(&self) -> CorpusResult<Vec<Subcorpus>> {
+        decode_subcorpora(self.read_reserved(SUBCORPORA_ID)?)
+    }
+    pub(crate) fn subcorpus(&self, name: &str) -> CorpusResult<Subcorpus> {
+        Subcorpora::from_bytes(self.read_reserved(SUBCORPORA_ID)?)?
+            .0
+            .remove(name)
+            .ok_or(CorpusError::SubcorpusNotFoundError(name.to_string()))
+            .and_then(Subcorpus::try_from)
+    }
+}
+
+impl CorpusState<WriteState> {
+    /// save `subcorpus`, replacing any existing definition with the same name
+    pub(crate) fn save_subcorpus(&self, subcorpus: &Subcorpus) -> CorpusResult<()> {
+        self.update_subcorpora(|all| {
+            all.0.insert(subcorpus.name.clone(), subcorpus.into());
+            Ok(())
+        })
+    }
+    pub(crate) fn delete_subcorpus(&self, name: &str) -> CorpusResult<()> {
+        self.update_subcorpora(|all| match all.0.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(CorpusError::SubcorpusNotFoundError(name.to_string())),
+        })
+    }
+    /// read, change and write back every definition under the write lock,
+    /// so concurrent changes can't undo each other. artifacts computed over
+    /// a subcorpus may no longer match its definition, so are dropped
+    fn update_subcorpora<F>(&self, f: F) -> CorpusResult<()>
+    where
+        F: FnOnce(&mut Subcorpora) -> CorpusResult<()>,
+    {
+        self.update_raw(|st| {
+            let db = st.db();
+            let mut all = Subcorpora::from_bytes(db.read(SUBCORPORA_ID)?.map(|raw| raw.to_vec()))?;
+            f(&mut all)?;
+            let mut batch = drop_artifacts(db)?;
+            batch.push((SUBCORPORA_ID, Some(all.to_bytes()?)));
+            Ok((batch, ()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::stats::FrequencyKey;
+    use crate::analysis::Scope;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::collection::HydratedCollection;
+    use crate::entities::document::HydratedDocument;
+    use crate::entities::token::HydratedToken;
+    use crate::entities::HydratedEntity;
+    use crate::marble::{_test_config, CorpusWrite};
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob("*", ""));
+        assert!(glob("Letter*", "Letter to Mary"));
+        assert!(glob("*to*", "Letter to Mary"));
+        assert!(glob("L?tter*y", "Letter to Mary"));
+        assert!(!glob("Letter", "Letter to Mary"));
+        assert!(!glob("*Jane*", "Letter to Mary"));
+    }
+
+    #[test]
+    fn subcorpus_scope() -> CorpusResult<()> {
+        let config = _test_config("subcorpus");
+        let date = |y| Utc.with_ymd_and_hms(y, 1, 1, 0, 0, 0).unwrap();
+        let document = |id: u128, author: u128, year: i32, title: &str| {
+            HydratedEntity::Document(HydratedDocument::new(
+                id,
+                author,
+                7,
+                date(year),
+                title.to_string(),
+            ))
+        };
+        let token = |id: u128, document: u128| {
+            HydratedEntity::Token(HydratedToken::new(id, document, 1, 0, 0, "x".into(), 0))
+        };
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(vec![
+            HydratedEntity::Author(HydratedAuthor::new(1, "Mary".into(), "notes".into())),
+            HydratedEntity::Author(HydratedAuthor::new(2, "John".into(), "notes".into())),
+            HydratedEntity::Collection(HydratedCollection::new(
+                7,
+                date(1850),
+                "letters".into(),
+                "notes".into(),
+            )),
+            document(10, 1, 1860, "Letter to Mary"),
+            document(11, 1, 1910, "Letter to John"),
+            document(12, 2, 1870, "Letter to Jane"),
+            token(20, 10),
+            token(21, 11),
+            token(22, 12),
+            token(23, 12),
+        ])?;
+        let victorian = Subcorpus {
+            author_ids: vec![1, 2],
+            from: Some(date(1850)),
+            to: Some(date(1900)),
+            title_pattern: Some("Letter*".to_string()),
+            ..Subcorpus::new("victorian")
+        };
+        writer.save_subcorpus(&victorian)?;
+        writer.save_subcorpus(&Subcorpus::new("scratch"))?;
+        writer.delete_subcorpus("scratch")?;
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        assert_eq!(reader.subcorpora()?, vec![victorian]);
+        let scope = Scope::Subcorpus("victorian".to_string());
+        assert_eq!(reader.frequencies(&scope, FrequencyKey::Text)?.tokens(), 3);
+        match reader.frequencies(&Scope::Subcorpus("missing".into()), FrequencyKey::Text) {
+            Err(CorpusError::SubcorpusNotFoundError(name)) if name == "missing" => (),
+            r => panic!("missing subcorpus resolved: {r:?}"),
+        }
+        Ok(())
+    }
+}
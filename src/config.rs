@@ -0,0 +1,399 @@
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::paging::PagingPolicy;
+use crate::marble::pf;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml_edit::{value, Array, Document, Item, Table};
+
+/// settings file kept in the corpus directory
+pub const CONFIG_FILE: &str = "corpus.toml";
+/// label schemas this build can read
+pub const LABEL_SCHEMAS: &[&str] = &["pos"];
+
+/// tuning passed through to marble
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarbleSettings {
+    /// zstd level from 1 to 22, or `None` to store uncompressed
+    pub zstd_compression_level: Option<i32>,
+    pub target_file_size: usize,
+    pub file_compaction_percent: u8,
+    /// marble refuses objects bigger than this, pages included
+    pub max_object_size: usize,
+    pub small_file_cleanup_threshold: usize,
+    pub min_compaction_files: usize,
+}
+
+impl Default for MarbleSettings {
+    fn default() -> Self {
+        Self {
+            zstd_compression_level: None,
+            target_file_size: 512_000_000,
+            file_compaction_percent: 20,
+            max_object_size: 1_024_000,
+            small_file_cleanup_threshold: 128,
+            min_compaction_files: 128,
+        }
+    }
+}
+
+/// everything needed to open a corpus. `load` reads `corpus.toml` from the
+/// corpus directory, then lets the `MARBLE_*` environment variables
+/// override it
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorpusConfig {
+    pub path: PathBuf,
+    pub marble: MarbleSettings,
+    /// bytes of pages and strings each reader keeps cached
+    pub cache_bytes: usize,
+    pub paging: PagingPolicy,
+    /// the label schemas tokens are labelled with, from `LABEL_SCHEMAS`
+    pub label_schemas: Vec<String>,
+    /// settings for whatever tokenizes text going into the corpus. they are
+    /// kept here so they travel with it, but aren't interpreted
+    pub tokenizer: BTreeMap<String, String>,
+}
+
+impl CorpusConfig {
+    /// defaults for a corpus at `path`, ignoring any file or environment
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            marble: MarbleSettings::default(),
+            cache_bytes: 256_000_000,
+            paging: PagingPolicy::default(),
+            label_schemas: vec!["pos".to_string()],
+            tokenizer: BTreeMap::new(),
+        }
+    }
+    /// the corpus directory named by `MARBLE_PATH`, or `corpus`
+    pub fn default_path() -> PathBuf {
+        PathBuf::from(env::var("MARBLE_PATH").unwrap_or_else(|_| "corpus".to_string()))
+    }
+    /// settings for the corpus at `path`: defaults, then its `corpus.toml`
+    /// if it has one, then the environment. errors if the result is invalid
+    pub fn load<P: AsRef<Path>>(path: P) -> CorpusResult<Self> {
+        let mut config = Self::new(path);
+        let file = config.path.join(CONFIG_FILE);
+        if file.exists() {
+            config.read_toml(&fs::read_to_string(file)?)?;
+        }
+        config.read_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// apply the settings in `text`
+    fn read_toml(&mut self, text: &str) -> CorpusResult<()> {
+        let doc = text
+            .parse::<Document>()
+            .map_err(|e| config_error(format!("{CONFIG_FILE} is not valid TOML: {e}")))?;
+        let root = doc.as_table();
+        check_keys(
+            root,
+            "",
+            &["marble", "cache", "paging", "labels", "tokenizer"],
+        )?;
+        if let Some(t) = section(root, "marble")? {
+            check_keys(
+                t,
+                "marble.",
+                &[
+                    "zstd_compression_level",
+                    "target_file_size",
+                    "file_compaction_percent",
+                    "max_object_size",
+                    "small_file_cleanup_threshold",
+                    "min_compaction_files",
+                ],
+            )?;
+            let m = &mut self.marble;
+            m.zstd_compression_level = integer(t, "marble.", "zstd_compression_level")?;
+            set(
+                &mut m.target_file_size,
+                integer(t, "marble.", "target_file_size")?,
+            );
+            set(
+                &mut m.file_compaction_percent,
+                integer(t, "marble.", "file_compaction_percent")?,
+            );
+            set(
+                &mut m.max_object_size,
+                integer(t, "marble.", "max_object_size")?,
+            );
+            set(
+                &mut m.small_file_cleanup_threshold,
+                integer(t, "marble.", "small_file_cleanup_threshold")?,
+            );
+            set(
+                &mut m.min_compaction_files,
+                integer(t, "marble.", "min_compaction_files")?,
+            );
+        }
+        if let Some(t) = section(root, "cache")? {
+            check_keys(t, "cache.", &["bytes"])?;
+            set(&mut self.cache_bytes, integer(t, "cache.", "bytes")?);
+        }
+        if let Some(t) = section(root, "paging")? {
+            check_keys(t, "paging.", &["max_entities", "max_bytes"])?;
+            set(
+                &mut self.paging.max_entities,
+                integer(t, "paging.", "max_entities")?,
+            );
+            set(
+                &mut self.paging.max_bytes,
+                integer(t, "paging.", "max_bytes")?,
+            );
+        }
+        if let Some(t) = section(root, "labels")? {
+            check_keys(t, "labels.", &["schemas"])?;
+            if let Some(item) = t.get("schemas") {
+                let schemas = item
+                    .as_array()
+                    .ok_or_else(|| config_error("labels.schemas must be an array of strings"))?;
+                self.label_schemas = schemas
+                    .iter()
+                    .map(|s| {
+                        s.as_str().map(String::from).ok_or_else(|| {
+                            config_error("labels.schemas must be an array of strings")
+                        })
+                    })
+                    .collect::<CorpusResult<Vec<String>>>()?;
+            }
+        }
+        if let Some(t) = section(root, "tokenizer")? {
+            for (key, item) in t.iter() {
+                let v = item
+                    .as_str()
+                    .ok_or_else(|| config_error(format!("tokenizer.{key} must be a string")))?;
+                self.tokenizer.insert(key.to_string(), v.to_string());
+            }
+        }
+        Ok(())
+    }
+    /// apply any `MARBLE_*` overrides
+    fn read_env(&mut self) -> CorpusResult<()> {
+        let m = &mut self.marble;
+        if let Some(level) = env_var("ZSTD_COMPRESSION_LEVEL")? {
+            m.zstd_compression_level = Some(level);
+        }
+        set(&mut m.target_file_size, env_var("TARGET_FILE_SIZE")?);
+        set(
+            &mut m.file_compaction_percent,
+            env_var("FILE_COMPACTION_SIZE")?,
+        );
+        set(&mut m.max_object_size, env_var("MAX_OBJECT_SIZE")?);
+        set(
+            &mut m.small_file_cleanup_threshold,
+            env_var("SMALL_FILE_CLEANUP_THRESHOLD")?,
+        );
+        set(
+            &mut m.min_compaction_files,
+            env_var("MIN_COMPACTION_FILES")?,
+        );
+        set(&mut self.cache_bytes, env_var("CACHE_BYTES")?);
+        set(&mut self.paging.max_entities, env_var("PAGE_MAX_ENTITIES")?);
+        set(&mut self.paging.max_bytes, env_var("PAGE_MAX_BYTES")?);
+        Ok(())
+    }
+    /// error on settings that can't work together or at all
+    pub fn validate(&self) -> CorpusResult<()> {
+        let m = &self.marble;
+        if let Some(level) = m.zstd_compression_level {
+            if !(1..=22).contains(&level) {
+                return Err(config_error(format!(
+                    "marble.zstd_compression_level must be between 1 and 22, not {level}"
+                )));
+            }
+        }
+        if m.file_compaction_percent > 100 {
+            return Err(config_error(format!(
+                "marble.file_compaction_percent must be at most 100, not {}",
+                m.file_compaction_percent
+            )));
+        }
+        for (key, v) in [
+            ("marble.target_file_size", m.target_file_size),
+            ("marble.max_object_size", m.max_object_size),
+            ("marble.min_compaction_files", m.min_compaction_files),
+        ] {
+            if v == 0 {
+                return Err(config_error(format!("{key} must be greater than 0")));
+            }
+        }
+        if self.paging.max_bytes > m.max_object_size {
+            return Err(config_error(format!(
+                "paging.max_bytes must be at most marble.max_object_size ({}), not {}",
+                m.max_object_size, self.paging.max_bytes
+            )));
+        }
+        for schema in self.label_schemas.iter() {
+            if !LABEL_SCHEMAS.contains(&schema.as_str()) {
+                return Err(config_error(format!(
+                    "unknown label schema {schema:?}; known schemas are {}",
+                    LABEL_SCHEMAS.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn marble_config(&self) -> marble::Config {
+        let m = &self.marble;
+        marble::Config {
+            path: self.path.clone(),
+            zstd_compression_level: m.zstd_compression_level,
+            fsync_each_batch: true,
+            target_file_size: m.target_file_size,
+            file_compaction_percent: m.file_compaction_percent,
+            max_object_size: m.max_object_size,
+            small_file_cleanup_threshold: m.small_file_cleanup_threshold,
+            min_compaction_files: m.min_compaction_files,
+            partition_function: pf,
+        }
+    }
+    /// every setting except the path, as `corpus.toml` would hold it
+    pub fn to_toml(&self) -> String {
+        let mut doc = Document::new();
+        let m = &self.marble;
+        let mut marble = Table::new();
+        if let Some(level) = m.zstd_compression_level {
+            marble["zstd_compression_level"] = value(level as i64);
+        }
+        marble["target_file_size"] = value(m.target_file_size as i64);
+        marble["file_compaction_percent"] = value(m.file_compaction_percent as i64);
+        marble["max_object_size"] = value(m.max_object_size as i64);
+        marble["small_file_cleanup_threshold"] = value(m.small_file_cleanup_threshold as i64);
+        marble["min_compaction_files"] = value(m.min_compaction_files as i64);
+        doc["marble"] = Item::Table(marble);
+        let mut cache = Table::new();
+        cache["bytes"] = value(self.cache_bytes as i64);
+        doc["cache"] = Item::Table(cache);
+        let mut paging = Table::new();
+        paging["max_entities"] = value(self.paging.max_entities as i64);
+        paging["max_bytes"] = value(self.paging.max_bytes as i64);
+        doc["paging"] = Item::Table(paging);
+        let mut labels = Table::new();
+        labels["schemas"] = value(self.label_schemas.iter().collect::<Array>());
+        doc["labels"] = Item::Table(labels);
+        let mut tokenizer = Table::new();
+        for (k, v) in self.tokenizer.iter() {
+            tokenizer[k.as_str()] = value(v.as_str());
+        }
+        doc["tokenizer"] = Item::Table(tokenizer);
+        doc.to_string()
+    }
+    /// write `corpus.toml` into the corpus directory
+    pub fn save(&self) -> CorpusResult<()> {
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.path.join(CONFIG_FILE), self.to_toml())?;
+        Ok(())
+    }
+}
+
+fn config_error<S: Into<String>>(msg: S) -> CorpusError {
+    CorpusError::ConfigurationError(msg.into())
+}
+
+fn set<T>(setting: &mut T, v: Option<T>) {
+    if let Some(v) = v {
+        *setting = v;
+    }
+}
+
+fn check_keys(table: &Table, prefix: &str, known: &[&str]) -> CorpusResult<()> {
+    match table.iter().find(|(key, _)| !known.contains(key)) {
+        Some((key, _)) => Err(config_error(format!(
+            "unknown setting {prefix}{key} in {CONFIG_FILE}"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn section<'a>(root: &'a Table, name: &str) -> CorpusResult<Option<&'a Table>> {
+    root.get(name)
+        .map(|item| {
+            item.as_table()
+                .ok_or_else(|| config_error(format!("{name} must be a table")))
+        })
+        .transpose()
+}
+
+fn integer<T: TryFrom<i64>>(table: &Table, prefix: &str, key: &str) -> CorpusResult<Option<T>> {
+    table
+        .get(key)
+        .map(|item| {
+            item.as_integer()
+                .and_then(|v| T::try_from(v).ok())
+                .ok_or_else(|| config_error(format!("{prefix}{key} is not a valid number")))
+        })
+        .transpose()
+}
+
+/// `MARBLE_{label}`, if set
+fn env_var<T: FromStr>(label: &str) -> CorpusResult<Option<T>> {
+    env::var(format!("MARBLE_{label}"))
+        .ok()
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|_| config_error(format!("Invalid MARBLE_{label}")))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn config_file_round_trip() -> CorpusResult<()> {
+        let path = std::env::temp_dir().join(format!("corpus-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        assert_eq!(CorpusConfig::load(&path)?, CorpusConfig::new(&path));
+        let mut config = CorpusConfig::new(&path);
+        config.marble.zstd_compression_level = Some(3);
+        config.marble.max_object_size = 2_000_000;
+        config.cache_bytes = 1024;
+        config.paging.max_entities = 100;
+        config
+            .tokenizer
+            .insert("lowercase".to_string(), "true".to_string());
+        config.save()?;
+        let mut expected = config.clone();
+        assert_eq!(CorpusConfig::load(&path)?, expected);
+        // paging.max_bytes of 0 means no limit
+        fs::write(
+            path.join(CONFIG_FILE),
+            "[marble]\nmax_object_size = 5000\n[paging]\nmax_bytes = 0\n",
+        )?;
+        expected = CorpusConfig::new(&path);
+        expected.marble.max_object_size = 5000;
+        assert_eq!(CorpusConfig::load(&path)?, expected);
+        for (text, message) in [
+            (
+                "[marble]\nzstd_level = 3\n",
+                "unknown setting marble.zstd_level",
+            ),
+            ("[cache]\nbytes = -1\n", "cache.bytes is not a valid number"),
+            (
+                "[marble]\nzstd_compression_level = 40\n",
+                "marble.zstd_compression_level must be between 1 and 22, not 40",
+            ),
+            (
+                "[paging]\nmax_bytes = 2000000\n",
+                "paging.max_bytes must be at most marble.max_object_size",
+            ),
+            (
+                "[labels]\nschemas = [\"ner\"]\n",
+                "unknown label schema \"ner\"",
+            ),
+        ] {
+            fs::write(path.join(CONFIG_FILE), text)?;
+            match CorpusConfig::load(&path) {
+                Err(CorpusError::ConfigurationError(e)) => assert!(e.starts_with(message), "{e}"),
+                r => panic!("accepted {text:?}: {r:?}"),
+            }
+        }
+        let _ = fs::remove_dir_all(path);
+        Ok(())
+    }
+}
@@ -2,7 +2,8 @@ use serde_derive::{Deserialize, Serialize};
 use std::u128;
 
 use crate::entities::{
-    strings::Strings, u128_id, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType, StringRef,
+    strings::Strings, u128_id, CorpusEntity, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType,
+    StringRef,
 };
 use crate::errors::CorpusResult;
 use minicbor::{Decode, Encode};
@@ -25,6 +26,12 @@ impl Author {
             notes: self.notes.hydrate(strings)?,
         }))
     }
+    pub(crate) fn name(&self) -> StringRef {
+        self.name
+    }
+    pub(crate) fn string_refs_mut(&mut self) -> Vec<&mut StringRef> {
+        vec![&mut self.name, &mut self.notes]
+    }
 }
 
 impl HasId for Author {
@@ -48,6 +55,29 @@ pub struct HydratedAuthor {
     notes: String,
 }
 
+impl HydratedAuthor {
+    pub fn new(id: u128, name: String, notes: String) -> Self {
+        Self { id, name, notes }
+    }
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+    pub fn notes(&self) -> &str {
+        self.notes.as_str()
+    }
+    /// the same author under `id`
+    pub(crate) fn with_id(&self, id: u128) -> Self {
+        Self { id, ..self.clone() }
+    }
+    pub(crate) fn dehydrate(&self, strings: &mut Strings) -> CorpusResult<CorpusEntity> {
+        Ok(CorpusEntity::Author(Author {
+            id: self.id.to_be_bytes(),
+            name: StringRef::dehydrate(&self.name, strings)?,
+            notes: StringRef::dehydrate(&self.notes, strings)?,
+        }))
+    }
+}
+
 impl HasId for HydratedAuthor {
     fn id(&self) -> u128 {
         self.id
@@ -1,6 +1,6 @@
 use crate::entities::{
-    parse_date, strings::Strings, u128_id, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType,
-    StringRef,
+    parse_date, strings::Strings, u128_id, CorpusEntity, HasId, HasObjId, HasType, HydratedEntity,
+    Id, ObjType, StringRef,
 };
 use crate::errors::CorpusResult;
 use chrono::{DateTime, Utc};
@@ -29,6 +29,15 @@ impl Collection {
             notes: self.notes.hydrate(strings)?,
         }))
     }
+    pub(crate) fn date(&self) -> u64 {
+        self.date
+    }
+    pub(crate) fn title(&self) -> StringRef {
+        self.title
+    }
+    pub(crate) fn string_refs_mut(&mut self) -> Vec<&mut StringRef> {
+        vec![&mut self.title, &mut self.notes]
+    }
 }
 
 impl HasId for Collection {
@@ -53,6 +62,29 @@ pub struct HydratedCollection {
     notes: String,
 }
 
+impl HydratedCollection {
+    pub fn new(id: u128, date: DateTime<Utc>, title: String, notes: String) -> Self {
+        Self {
+            id,
+            date,
+            title,
+            notes,
+        }
+    }
+    /// the same collection under `id`
+    pub(crate) fn with_id(&self, id: u128) -> Self {
+        Self { id, ..self.clone() }
+    }
+    pub(crate) fn dehydrate(&self, strings: &mut Strings) -> CorpusResult<CorpusEntity> {
+        Ok(CorpusEntity::Collection(Collection {
+            id: self.id.to_be_bytes(),
+            date: self.date.timestamp() as u64,
+            title: StringRef::dehydrate(&self.title, strings)?,
+            notes: StringRef::dehydrate(&self.notes, strings)?,
+        }))
+    }
+}
+
 impl HasId for HydratedCollection {
     fn id(&self) -> u128 {
         self.id
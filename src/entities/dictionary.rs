@@ -0,0 +1,134 @@
+use crate::errors::{CorpusError, CorpusResult};
+use minicbor::{Decode, Encode};
+use std::collections::HashMap;
+
+/// corpus-wide table of frequent strings. a string's code is `base` plus
+/// its position. the first `retired` strings are left over from the
+/// previous dictionary while pages are rewritten: they still resolve, but
+/// new references never use them
+#[derive(Debug, Default, PartialEq)]
+pub struct Dictionary {
+    base: u64,
+    retired: usize,
+    strings: Vec<Vec<u8>>,
+    codes: HashMap<Vec<u8>, u64>,
+}
+
+/// what's stored under `DICTIONARY_ID`
+#[derive(Encode, Decode)]
+struct StoredDictionary {
+    #[n(0)]
+    base: u64,
+    #[n(1)]
+    retired: u64,
+    #[n(2)]
+    strings: Vec<Vec<u8>>,
+}
+
+impl Dictionary {
+    /// the `k` most frequent of `counts`, ties broken by content
+    pub(crate) fn from_counts(counts: HashMap<Vec<u8>, u64>, k: usize) -> Self {
+        let mut counts = counts.into_iter().collect::<Vec<(Vec<u8>, u64)>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(k);
+        Self::from_strings(0, 0, counts.into_iter().map(|(s, _)| s).collect())
+    }
+    fn from_strings(base: u64, retired: usize, strings: Vec<Vec<u8>>) -> Self {
+        let codes = strings
+            .iter()
+            .enumerate()
+            .skip(retired)
+            .map(|(code, s)| (s.clone(), base + code as u64))
+            .collect();
+        Self {
+            base,
+            retired,
+            strings,
+            codes,
+        }
+    }
+    /// `self` followed by `next`, whose strings get codes after all of
+    /// ours. all of our strings are retired
+    pub(crate) fn staged(&self, next: &Dictionary) -> Self {
+        let mut strings = self.strings.clone();
+        strings.extend(next.strings.iter().cloned());
+        Self::from_strings(self.base, self.strings.len(), strings)
+    }
+    /// the staged dictionary with its retired strings dropped, once no
+    /// page refers to them any more
+    pub(crate) fn settled(&self) -> Self {
+        Self::from_strings(
+            self.base + self.retired as u64,
+            0,
+            self.strings[self.retired..].to_vec(),
+        )
+    }
+    pub(crate) fn code(&self, slice: &[u8]) -> Option<u64> {
+        self.codes.get(slice).copied()
+    }
+    pub(crate) fn get(&self, code: u64) -> Option<&[u8]> {
+        let i = code.checked_sub(self.base)?;
+        self.strings.get(i as usize).map(|s| s.as_slice())
+    }
+    /// number of strings new references can use
+    pub(crate) fn len(&self) -> usize {
+        self.strings.len() - self.retired
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub(crate) fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let stored = StoredDictionary {
+            base: self.base,
+            retired: self.retired as u64,
+            strings: self.strings.clone(),
+        };
+        let mut v = Vec::new();
+        minicbor::encode(&stored, &mut v)
+            .map_err(|_| CorpusError::EncodingError("Dictionary encoding error".to_string()))?;
+        Ok(v)
+    }
+    /// errors if `retired` counts more strings than there are, or codes
+    /// would run past `u64::MAX`, rather than panicking later on
+    pub(crate) fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        let stored = minicbor::decode::<StoredDictionary>(raw)
+            .map_err(|_| CorpusError::DecodingError("loading dictionary".to_string()))?;
+        let len = stored.strings.len() as u64;
+        if stored.retired > len || stored.base.checked_add(len).is_none() {
+            return Err(CorpusError::DecodingError(format!(
+                "dictionary of {len} strings from {} with {} retired",
+                stored.base, stored.retired
+            )));
+        }
+        Ok(Self::from_strings(
+            stored.base,
+            stored.retired as usize,
+            stored.strings,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_dictionaries_rejected() -> CorpusResult<()> {
+        let dictionary = Dictionary::from_strings(3, 1, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(Dictionary::from_bytes(&dictionary.to_bytes()?)?, dictionary);
+        for (base, retired) in [(0, 3), (u64::MAX, 0)] {
+            let bad = StoredDictionary {
+                base,
+                retired,
+                strings: vec![b"a".to_vec(), b"b".to_vec()],
+            };
+            let mut raw = Vec::new();
+            minicbor::encode(&bad, &mut raw).expect("encoded");
+            match Dictionary::from_bytes(&raw) {
+                Err(CorpusError::DecodingError(_)) => (),
+                r => panic!("decoded a bad dictionary: {r:?}"),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use crate::entities::{
-    parse_date, strings::Strings, u128_id, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType,
-    StringRef,
+    parse_date, strings::Strings, u128_id, CorpusEntity, HasId, HasObjId, HasType, HydratedEntity,
+    Id, ObjType, StringRef,
 };
 use crate::errors::CorpusResult;
 use chrono::{DateTime, Utc};
@@ -35,6 +35,21 @@ impl Document {
             title: self.title.hydrate(strings)?,
         }))
     }
+    pub(crate) fn author_id(&self) -> u128 {
+        u128_id(&self.author_id)
+    }
+    pub(crate) fn collection_id(&self) -> u128 {
+        u128_id(&self.collection_id)
+    }
+    pub(crate) fn date(&self) -> u64 {
+        self.date
+    }
+    pub(crate) fn title(&self) -> StringRef {
+        self.title
+    }
+    pub(crate) fn string_refs_mut(&mut self) -> Vec<&mut StringRef> {
+        vec![&mut self.title]
+    }
 }
 
 impl HasId for Document {
@@ -60,6 +75,49 @@ pub struct HydratedDocument {
     title: String,
 }
 
+impl HydratedDocument {
+    pub fn new(
+        id: u128,
+        author_id: u128,
+        collection_id: u128,
+        date: DateTime<Utc>,
+        title: String,
+    ) -> Self {
+        Self {
+            id,
+            author_id,
+            collection_id,
+            date,
+            title,
+        }
+    }
+    pub fn author_id(&self) -> u128 {
+        self.author_id
+    }
+    pub fn collection_id(&self) -> u128 {
+        self.collection_id
+    }
+    /// the same document under `id`, referring to `author_id` and
+    /// `collection_id` instead
+    pub(crate) fn remapped(&self, id: u128, author_id: u128, collection_id: u128) -> Self {
+        Self {
+            id,
+            author_id,
+            collection_id,
+            ..self.clone()
+        }
+    }
+    pub(crate) fn dehydrate(&self, strings: &mut Strings) -> CorpusResult<CorpusEntity> {
+        Ok(CorpusEntity::Document(Document {
+            id: self.id.to_be_bytes(),
+            author_id: self.author_id.to_be_bytes(),
+            collection_id: self.collection_id.to_be_bytes(),
+            date: self.date.timestamp() as u64,
+            title: StringRef::dehydrate(&self.title, strings)?,
+        }))
+    }
+}
+
 impl HasId for HydratedDocument {
     fn id(&self) -> u128 {
         self.id
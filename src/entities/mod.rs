@@ -7,10 +7,12 @@ use serde_derive::{Deserialize, Serialize};
 
 pub(crate) mod author;
 pub(crate) mod collection;
+pub(crate) mod dictionary;
 pub(crate) mod document;
 pub(crate) mod string_ref;
 pub(crate) mod strings;
 pub(crate) mod token;
+pub(crate) mod token_columns;
 
 pub use author::Author;
 pub use collection::Collection;
@@ -28,8 +30,8 @@ pub enum CorpusEntity {
     Document(#[n(0)] Document),
     #[n(3)]
     Token(#[n(0)] Token),
-    #[n(4)]
-    StringRef(#[n(0)] StringRef),
+    // 4 was StringRef. strings aren't entities: they're reached through the
+    // page's `Strings` and the corpus dictionary
 }
 
 impl CorpusEntity {
@@ -39,7 +41,6 @@ impl CorpusEntity {
             Self::Collection(_) => ObjType::Collection,
             Self::Document(_) => ObjType::Document,
             Self::Token(_) => ObjType::Token,
-            Self::StringRef(_) => ObjType::StringRef,
         }
     }
     pub fn id(&self) -> u128 {
@@ -48,7 +49,6 @@ impl CorpusEntity {
             Self::Collection(c) => c.id(),
             Self::Document(d) => d.id(),
             Self::Token(t) => t.id(),
-            Self::StringRef(s) => s.id(),
         }
     }
     fn len(&self) -> usize {
@@ -56,7 +56,6 @@ impl CorpusEntity {
             Self::Author(_) => 80,
             Self::Collection(_) => 96,
             Self::Document(_) => 96,
-            Self::StringRef(_) => 32,
             Self::Token(_) => 128,
         }
     }
@@ -69,10 +68,6 @@ impl CorpusEntity {
                 .map_err(|_| CorpusError::EncodingError(format!("{:?}", self)))?,
             Self::Document(ref d) => minicbor::encode::<&Document, &mut Vec<u8>>(d, b.as_mut())
                 .map_err(|_| CorpusError::EncodingError(format!("{:?}", self)))?,
-            Self::StringRef(ref s) => {
-                minicbor::encode::<&StringRef, &mut Vec<u8>>(s, b.as_mut())
-                    .map_err(|_| CorpusError::EncodingError(format!("{:?}", self)))?
-            }
             Self::Token(ref t) => minicbor::encode::<&Token, &mut Vec<u8>>(t, b.as_mut())
                 .map_err(|_| CorpusError::EncodingError(format!("{:?}", self)))?,
         };
@@ -84,6 +79,19 @@ impl CorpusEntity {
     pub(crate) fn page_id(&self) -> u64 {
         self.obj_id().0
     }
+    /// every reference this entity holds into its page's strings
+    pub(crate) fn string_refs_mut(&mut self) -> Vec<&mut StringRef> {
+        match self {
+            Self::Author(ref mut a) => a.string_refs_mut(),
+            Self::Collection(ref mut c) => c.string_refs_mut(),
+            Self::Document(ref mut d) => d.string_refs_mut(),
+            Self::Token(ref mut t) => t.string_refs_mut(),
+        }
+    }
+    pub(crate) fn string_refs(&self) -> Vec<StringRef> {
+        let mut entity = *self;
+        entity.string_refs_mut().into_iter().map(|r| *r).collect()
+    }
     pub(crate) fn hydrate(
         &self,
         strings: &crate::entities::strings::Strings,
@@ -93,9 +101,22 @@ impl CorpusEntity {
             Self::Collection(ref c) => c.hydrate(strings),
             Self::Document(ref d) => d.hydrate(strings),
             Self::Token(ref t) => t.hydrate(strings),
-            Self::StringRef(_) => Err(CorpusError::DecodingError(
-                "Cannot hydrate string_ref".to_string(),
-            )),
+        }
+    }
+}
+
+impl HasType for CorpusEntity {
+    fn obj_type(&self) -> ObjType {
+        CorpusEntity::obj_type(self)
+    }
+}
+
+impl HasReferences for CorpusEntity {
+    fn referenced_ids(&self) -> Option<(u128, u128)> {
+        match self {
+            Self::Document(ref d) => Some((d.author_id(), d.collection_id())),
+            Self::Token(ref t) => Some((t.document_id(), t.author_id())),
+            _ => None,
         }
     }
 }
@@ -110,6 +131,14 @@ pub enum HydratedEntity {
 }
 
 impl HydratedEntity {
+    pub fn obj_type(&self) -> ObjType {
+        match self {
+            Self::Author(_) => ObjType::Author,
+            Self::Collection(_) => ObjType::Collection,
+            Self::Document(_) => ObjType::Document,
+            Self::Token(_) => ObjType::Token,
+        }
+    }
     pub fn obj_id(&self) -> (u64, u64) {
         match self {
             Self::Author(ref a) => a.obj_id(),
@@ -118,16 +147,42 @@ impl HydratedEntity {
             Self::Token(ref t) => t.obj_id(),
         }
     }
+    pub(crate) fn dehydrate(
+        &self,
+        strings: &mut crate::entities::strings::Strings,
+    ) -> CorpusResult<CorpusEntity> {
+        match self {
+            Self::Author(ref a) => a.dehydrate(strings),
+            Self::Collection(ref c) => c.dehydrate(strings),
+            Self::Document(ref d) => d.dehydrate(strings),
+            Self::Token(ref t) => t.dehydrate(strings),
+        }
+    }
+}
+
+impl HasType for HydratedEntity {
+    fn obj_type(&self) -> ObjType {
+        HydratedEntity::obj_type(self)
+    }
+}
+
+impl HasReferences for HydratedEntity {
+    fn referenced_ids(&self) -> Option<(u128, u128)> {
+        match self {
+            Self::Document(ref d) => Some((d.author_id(), d.collection_id())),
+            Self::Token(ref t) => Some((t.document_id(), t.author_id())),
+            _ => None,
+        }
+    }
 }
 
 #[repr(u64)]
-#[derive(Debug, Eq, FromPrimitive, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, FromPrimitive, Hash, PartialEq, Ord, PartialOrd)]
 pub enum ObjType {
     Author = 0x0000_0000_0000_0000,
     Collection = 0x1000_0000_0000_0000,
     Document = 0x2000_0000_0000_0000,
     Token = 0x3000_0000_0000_0000,
-    StringRef = 0x4000_0000_0000_0000,
 }
 
 pub type Id = [u8; 16];
@@ -146,6 +201,25 @@ pub(crate) trait HasObjId: HasId + HasType {
     }
 }
 
+/// entities that refer to others: documents to their author and
+/// collection, tokens to their document and author
+pub(crate) trait HasReferences: HasType {
+    /// the ids of what this refers to, in the order above
+    fn referenced_ids(&self) -> Option<(u128, u128)>;
+    /// (type, id) of every entity this one refers to
+    fn references(&self) -> Vec<(ObjType, u128)> {
+        match (self.obj_type(), self.referenced_ids()) {
+            (ObjType::Document, Some((author, collection))) => {
+                vec![(ObjType::Author, author), (ObjType::Collection, collection)]
+            }
+            (ObjType::Token, Some((document, author))) => {
+                vec![(ObjType::Document, document), (ObjType::Author, author)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
 pub fn u128_id(bytes: &[u8; 16]) -> u128 {
     u128::from_be_bytes(*bytes)
 }
@@ -205,4 +279,15 @@ mod test {
         assert_eq!(oh, 0x0000_0000_0000_0000);
         assert_eq!(ol, 0x3000_0000_0000_0001);
     }
+    #[test]
+    fn test_parse_obj_id() {
+        assert_eq!(
+            parse_obj_id(0x2000_0000_0000_0001).expect("document"),
+            ObjType::Document
+        );
+        match parse_obj_id(0x4000_0000_0000_0001) {
+            Err(CorpusError::InvalidEntityTypeError) => (),
+            r => panic!("string refs aren't entities: {r:?}"),
+        }
+    }
 }
@@ -1,8 +1,13 @@
-use crate::entities::{HasId, HasObjId, HasType, ObjType};
 use crate::errors::{CorpusError, CorpusResult};
 use minicbor::{Decode, Encode};
 
-#[derive(Copy, Clone, Debug, Decode, Encode, Eq, PartialEq)]
+/// set on `start` when the rest of it is a code in the corpus dictionary
+/// rather than an offset into the page's strings
+pub(crate) const SHARED_STRING_FLAG: u64 = 0x8000_0000_0000_0000;
+
+/// `length` bytes from `start`; any reference with length 0 is the empty
+/// string
+#[derive(Copy, Clone, Debug, Decode, Encode, Eq, Hash, PartialEq)]
 pub struct StringRef {
     #[n(0)]
     pub start: u64,
@@ -14,11 +19,32 @@ impl StringRef {
     pub fn new(start: u64, length: u64) -> Self {
         Self { start, length }
     }
+    /// the empty string, which takes up no bytes in any buffer
+    pub fn empty() -> Self {
+        Self::new(0, 0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    /// a reference to dictionary entry `code`
+    pub(crate) fn shared(code: u64, length: u64) -> Self {
+        Self::new(code | SHARED_STRING_FLAG, length)
+    }
+    pub(crate) fn is_shared(&self) -> bool {
+        self.start & SHARED_STRING_FLAG != 0
+    }
+    pub(crate) fn code(&self) -> Option<u64> {
+        self.is_shared().then_some(self.start & !SHARED_STRING_FLAG)
+    }
+    pub fn length(&self) -> u64 {
+        self.length
+    }
     pub fn start(&self) -> CorpusResult<usize> {
         self.start
             .try_into()
             .map_err(|_| CorpusError::StringNotFoundError(self.start, self.length))
     }
+    /// one past the last byte
     pub fn end(&self) -> CorpusResult<usize> {
         let start: usize = self
             .start
@@ -39,28 +65,13 @@ impl StringRef {
         strings.get_string(&self)
     }
     pub(crate) fn dehydrate(
-        &self,
-        string: String,
-        strings: &super::strings::Strings,
+        string: &str,
+        strings: &mut super::strings::Strings,
     ) -> CorpusResult<Self> {
-        todo!()
+        strings.intern(string.as_bytes())
     }
 }
 
-impl HasId for StringRef {
-    fn id(&self) -> u128 {
-        ((self.start as u128) << 64) & self.length as u128
-    }
-}
-
-impl HasType for StringRef {
-    fn obj_type(&self) -> ObjType {
-        ObjType::StringRef
-    }
-}
-
-impl HasObjId for StringRef {}
-
 impl PartialOrd for StringRef {
     fn partial_cmp(&self, other: &StringRef) -> Option<std::cmp::Ordering> {
         if self.start < other.start {
@@ -1,89 +1,231 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::str;
+use std::sync::Arc;
 
+use crate::entities::dictionary::Dictionary;
 use crate::entities::StringRef;
 use crate::errors::{CorpusError, CorpusResult};
 
-#[derive(Debug, Default, PartialEq)]
-pub struct Strings(Vec<u8>);
+/// a page's string bytes. strings added through `intern` are stored once
+/// and looked up by content afterwards
+#[derive(Debug, Default)]
+pub struct Strings {
+    bytes: Vec<u8>,
+    index: HashMap<Vec<u8>, StringRef>,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+impl PartialEq for Strings {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
 
 impl Strings {
     pub fn new() -> Self {
         Self::default()
     }
     pub fn append(&mut self, slice: &[u8]) {
-        self.0.extend_from_slice(slice);
+        self.bytes.extend_from_slice(slice);
+    }
+    /// append `slice` and return a reference to it
+    pub(crate) fn push(&mut self, slice: &[u8]) -> CorpusResult<StringRef> {
+        if slice.is_empty() {
+            return Ok(StringRef::empty());
+        }
+        let start = self.bytes.len() as u64;
+        self.append(slice);
+        Ok(StringRef::new(start, slice.len() as u64))
+    }
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
     }
-    fn gs(&self, start: usize, end: usize) -> CorpusResult<String> {
+    fn gs(&self, start: usize, end: usize) -> CorpusResult<&str> {
         let arr = self.gb(start, end)?;
-        let s = str::from_utf8(arr).map_err(|_| CorpusError::InvalidStringError(start, end))?;
-        Ok(String::from(s))
+        str::from_utf8(arr).map_err(|_| CorpusError::InvalidStringError(start, end))
     }
+    /// bytes `start..end`
     fn gb(&self, start: usize, end: usize) -> CorpusResult<&[u8]> {
-        self.0
-            .get(start..=end)
-            .ok_or(CorpusError::StringNotFoundError(start as u64, end as u64))
+        self.bytes
+            .get(start..end)
+            .ok_or(CorpusError::StringOutOfBoundsError(
+                start,
+                end,
+                self.bytes.len(),
+            ))
     }
     pub fn get_string(&self, string_ref: &StringRef) -> CorpusResult<String> {
+        self.get_str(string_ref).map(String::from)
+    }
+    /// like `get_string`, but borrowed from the page's bytes or the
+    /// dictionary instead of copied
+    pub fn get_str(&self, string_ref: &StringRef) -> CorpusResult<&str> {
+        if string_ref.is_shared() {
+            let bytes = self.get_bytes(string_ref)?;
+            return str::from_utf8(bytes).map_err(|_| {
+                CorpusError::InvalidStringError(string_ref.start as usize, bytes.len())
+            });
+        }
         self.gs(string_ref.start()?, string_ref.end()?)
     }
     pub(crate) fn get_bytes(&self, string_ref: &StringRef) -> CorpusResult<&[u8]> {
+        if let Some(code) = string_ref.code() {
+            return self.dictionary.as_ref().and_then(|d| d.get(code)).ok_or(
+                CorpusError::StringNotFoundError(string_ref.start, string_ref.length()),
+            );
+        }
         self.gb(string_ref.start()?, string_ref.end()?)
     }
+    /// resolve shared references through `dictionary`
+    pub(crate) fn with_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+    /// like `intern`, but refers to the corpus dictionary when it has `slice`
+    pub(crate) fn intern_shared(&mut self, slice: &[u8]) -> CorpusResult<StringRef> {
+        match self.dictionary.as_ref().and_then(|d| d.code(slice)) {
+            Some(code) => Ok(StringRef::shared(code, slice.len() as u64)),
+            None => self.intern(slice),
+        }
+    }
     pub fn from_file<P>(f: P) -> CorpusResult<Self>
     where
         P: AsRef<Path>,
     {
         std::fs::read(f)
-            .map(|a| Self(a))
+            .map(Self::from_vec)
             .map_err(|e| CorpusError::BackingStorageError(e))
     }
     pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
-        Self(Vec::from(bytes))
-    }
-    pub(crate) fn get_ref(&self, string: &[u8]) -> Option<StringRef> {
-        let mut start: usize = 0;
-        while start < &self.0.len() - string.len() {
-            let mut length: usize = 0;
-            loop {
-                if self.0[start + length] != string[length] {
-                    break;
-                } else if length == string.len() - 1 {
-                    return Some(StringRef::new(start as u64, length as u64));
-                } else {
-                    length += 1;
-                }
+        Self::from_vec(Vec::from(bytes))
+    }
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            index: HashMap::new(),
+            dictionary: None,
+        }
+    }
+    /// a reference to `slice`, appending it only if it hasn't been interned
+    /// or indexed already
+    pub(crate) fn intern(&mut self, slice: &[u8]) -> CorpusResult<StringRef> {
+        if let Some(string_ref) = self.index.get(slice) {
+            return Ok(*string_ref);
+        }
+        let string_ref = self.push(slice)?;
+        self.index.insert(slice.to_vec(), string_ref);
+        Ok(string_ref)
+    }
+    /// make an existing string available to `intern`, e.g. after loading a
+    /// page whose entities already refer to it
+    pub(crate) fn index(&mut self, string_ref: StringRef) -> CorpusResult<()> {
+        if string_ref.is_shared() || string_ref.is_empty() {
+            return Ok(());
+        }
+        let bytes = self.get_bytes(&string_ref)?.to_vec();
+        self.index.entry(bytes).or_insert(string_ref);
+        Ok(())
+    }
+    /// copy only the bytes covered by `live` into a new buffer. references
+    /// that overlap keep overlapping, so shared bytes are copied once
+    pub(crate) fn compact<'a, I>(&self, live: I) -> CorpusResult<(Strings, StringsUpdates)>
+    where
+        I: IntoIterator<Item = &'a StringRef>,
+    {
+        let mut spans = Vec::new();
+        for string_ref in live.into_iter().filter(|r| !r.is_shared() && !r.is_empty()) {
+            let (start, end) = (string_ref.start()?, string_ref.end()?);
+            if end > self.bytes.len() {
+                return Err(CorpusError::StringOutOfBoundsError(
+                    start,
+                    end,
+                    self.bytes.len(),
+                ));
             }
-            start += 1;
+            spans.push((start, end));
         }
-        None
+        spans.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        let mut compacted = Strings::new().with_dictionary(self.dictionary.clone());
+        let mut updates = Vec::with_capacity(merged.len());
+        for (start, end) in merged {
+            let new_start = compacted.bytes.len() as u64;
+            compacted.append(&self.bytes[start..end]);
+            updates.push(StringsUpdate {
+                old: (start as u64, end as u64),
+                new: (new_start, new_start + (end - start) as u64),
+            });
+        }
+        Ok((compacted, StringsUpdates(updates)))
     }
     #[cfg(test)]
     pub fn _test_contents(&self) -> &[u8] {
-        self.0.as_slice()
+        self.bytes.as_slice()
     }
     #[cfg(test)]
     pub fn _test_gs(&self, start: usize, end: usize) -> CorpusResult<String> {
-        self.gs(start, end)
+        self.gs(start, end).map(String::from)
     }
     #[cfg(test)]
     pub fn _test_from_str(s: &str) -> Self {
-        Self(Vec::from(s.as_bytes()))
+        Self::from_vec(Vec::from(s.as_bytes()))
     }
     #[cfg(test)]
     pub fn _test_from_vec(s: Vec<u8>) -> Self {
-        Self(s)
+        Self::from_vec(s)
     }
 }
 
+/// a live span `old` (`start..end`) that now sits at `new`
 #[derive(Debug, PartialEq, PartialOrd)]
-struct StringsUpdate {
+pub(crate) struct StringsUpdate {
     old: (u64, u64),
     new: (u64, u64),
 }
 
-impl StringsUpdate {}
-// todo: update string_ref::View.{start,end}
+impl StringsUpdate {
+    fn covers(&self, string_ref: &StringRef) -> bool {
+        string_ref.start >= self.old.0
+            && string_ref
+                .start
+                .checked_add(string_ref.length())
+                .is_some_and(|end| end <= self.old.1)
+    }
+}
+
+/// how every live span moved during a compaction, ordered by old position
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct StringsUpdates(Vec<StringsUpdate>);
+
+impl StringsUpdates {
+    /// where `string_ref` points after compaction
+    pub(crate) fn remap(&self, string_ref: &StringRef) -> CorpusResult<StringRef> {
+        if string_ref.is_shared() {
+            return Ok(*string_ref);
+        }
+        if string_ref.is_empty() {
+            return Ok(StringRef::empty());
+        }
+        let i = self.0.partition_point(|u| u.old.0 <= string_ref.start);
+        match i.checked_sub(1).map(|i| &self.0[i]) {
+            Some(u) if u.covers(string_ref) => Ok(StringRef::new(
+                u.new.0 + (string_ref.start - u.old.0),
+                string_ref.length(),
+            )),
+            _ => Err(CorpusError::StringNotFoundError(
+                string_ref.start,
+                string_ref.length(),
+            )),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -98,15 +240,16 @@ mod tests {
     #[test]
     fn strings_get_string() -> CorpusResult<()> {
         let s = Strings::_test_from_str("hellothere");
-        assert_eq!(s._test_gs(5, 9)?, String::from("there"));
+        assert_eq!(s._test_gs(5, 10)?, String::from("there"));
+        assert_eq!(s.get_string(&StringRef::empty())?, String::new());
         Ok(())
     }
     #[test]
     fn strings_get_string_oob() {
         let s = Strings::_test_from_str("hellothere");
-        match s._test_gs(6, 10) {
+        match s._test_gs(6, 11) {
             Ok(_) => panic!("oob test failed"),
-            Err(CorpusError::StringNotFoundError(st, en)) if st == 6 && en == 10 => (),
+            Err(CorpusError::StringOutOfBoundsError(6, 11, 10)) => (),
             Err(e) => panic!("oob test wrong error {e:?}"),
         }
     }
@@ -120,12 +263,61 @@ mod tests {
         }
     }
     #[test]
-    fn strings_get_ref() {
-        let s = Strings::_test_from_str("anacondagent");
-        let b = b"con";
-        let expected = StringRef::new(3, 2);
-        let actual = s.get_ref(b).expect("get_ref");
-        assert_eq!(expected, actual);
-        assert_eq!(s.get_bytes(&actual).unwrap(), b);
+    fn strings_compact() -> CorpusResult<()> {
+        let s = Strings::_test_from_str("deadhellodeadtherebye");
+        let hello = StringRef::new(4, 5);
+        let ell = StringRef::new(5, 3);
+        let there = StringRef::new(13, 5);
+        let bye = StringRef::new(18, 3);
+        let empty = StringRef::empty();
+        let (compacted, updates) = s.compact([&there, &hello, &ell, &bye, &empty])?;
+        assert_eq!(compacted._test_contents(), b"hellotherebye");
+        for (r, expected) in [
+            (hello, "hello"),
+            (ell, "ell"),
+            (there, "there"),
+            (bye, "bye"),
+            (empty, ""),
+        ] {
+            assert_eq!(compacted.get_string(&updates.remap(&r)?)?, expected);
+        }
+        match updates.remap(&StringRef::new(0, 3)) {
+            Err(CorpusError::StringNotFoundError(0, 3)) => (),
+            r => panic!("dead string remapped: {r:?}"),
+        }
+        Ok(())
+    }
+    #[test]
+    fn strings_index() -> CorpusResult<()> {
+        let mut s = Strings::_test_from_str("anacondagent");
+        let con = StringRef::new(3, 3);
+        let gent = StringRef::new(8, 4);
+        s.index(con)?;
+        s.index(gent)?;
+        assert_eq!(s.intern(b"con")?, con);
+        assert_eq!(s.intern(b"gent")?, gent);
+        assert_eq!(s.intern(b"")?, StringRef::empty());
+        // only indexed strings are found, not any match in the bytes
+        assert_eq!(s.intern(b"ana")?, StringRef::new(12, 3));
+        assert_eq!(s._test_contents(), b"anacondagentana");
+        match s.index(StringRef::new(14, 3)) {
+            Err(CorpusError::StringOutOfBoundsError(..)) => (),
+            r => panic!("indexed past the end: {r:?}"),
+        }
+        Ok(())
+    }
+    #[test]
+    fn strings_intern() -> CorpusResult<()> {
+        let mut s = Strings::new();
+        assert_eq!(s.intern(b"")?, StringRef::empty());
+        let the = s.intern(b"the")?;
+        let cat = s.intern(b"cat")?;
+        assert_eq!(s.intern(b"the")?, the);
+        assert_eq!(s._test_contents(), b"thecat");
+        let mut loaded = Strings::from_bytes(s.as_bytes());
+        loaded.index(cat)?;
+        assert_eq!(loaded.intern(b"cat")?, cat);
+        assert_eq!(loaded._test_contents(), b"thecat");
+        Ok(())
     }
 }
@@ -1,7 +1,9 @@
 use crate::entities::{
-    strings::Strings, u128_id, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType, StringRef,
+    strings::Strings, u128_id, CorpusEntity, HasId, HasObjId, HasType, HydratedEntity, Id, ObjType,
+    StringRef,
 };
-use crate::errors::CorpusResult;
+use crate::errors::{CorpusError, CorpusResult};
+use binary_layout::prelude::*;
 use minicbor::{Decode, Encode};
 use serde_derive::{Deserialize, Serialize};
 
@@ -24,6 +26,25 @@ pub struct Token {
 }
 
 impl Token {
+    pub(crate) fn new(
+        id: u128,
+        document_id: u128,
+        author_id: u128,
+        line: u64,
+        position: u64,
+        text: StringRef,
+        labels: u128,
+    ) -> Self {
+        Self {
+            id: id.to_be_bytes(),
+            document_id: document_id.to_be_bytes(),
+            author_id: author_id.to_be_bytes(),
+            line,
+            position,
+            text,
+            labels: labels.to_be_bytes(),
+        }
+    }
     pub(crate) fn hydrate(&self, strings: &Strings) -> CorpusResult<HydratedEntity> {
         let id = u128_id(&self.id);
         let document_id = u128_id(&self.document_id);
@@ -39,6 +60,147 @@ impl Token {
             labels,
         }))
     }
+    pub(crate) fn document_id(&self) -> u128 {
+        u128_id(&self.document_id)
+    }
+    pub(crate) fn author_id(&self) -> u128 {
+        u128_id(&self.author_id)
+    }
+    pub(crate) fn line(&self) -> u64 {
+        self.line
+    }
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+    pub(crate) fn text(&self) -> StringRef {
+        self.text
+    }
+    pub(crate) fn string_refs_mut(&mut self) -> Vec<&mut StringRef> {
+        vec![&mut self.text]
+    }
+    pub(crate) fn labels(&self) -> u128 {
+        u128::from_be_bytes(self.labels)
+    }
+    /// pack into `record`, which must be `TOKEN_RECORD_SIZE` bytes
+    pub(crate) fn write_record(&self, record: &mut [u8]) {
+        let mut view = token_record::View::new(record);
+        view.id_mut().copy_from_slice(&self.id);
+        view.document_id_mut().copy_from_slice(&self.document_id);
+        view.author_id_mut().copy_from_slice(&self.author_id);
+        view.line_mut().write(self.line);
+        view.position_mut().write(self.position);
+        view.text_start_mut().write(self.text.start);
+        view.text_length_mut().write(self.text.length());
+        view.labels_mut().copy_from_slice(&self.labels);
+    }
+}
+
+define_layout!(token_record, BigEndian, {
+    id: [u8; 16],
+    document_id: [u8; 16],
+    author_id: [u8; 16],
+    line: u64,
+    position: u64,
+    text_start: u64,
+    text_length: u64,
+    labels: [u8; 16],
+});
+
+pub(crate) const TOKEN_RECORD_SIZE: usize = match token_record::SIZE {
+    Some(size) => size,
+    None => panic!("token records are fixed-size"),
+};
+
+/// tokens packed back to back as fixed-size records, read in place
+#[derive(Debug, Default)]
+pub(crate) struct TokenRecords(Vec<u8>);
+
+impl TokenRecords {
+    pub(crate) fn from_tokens<'a, I>(tokens: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Token>,
+    {
+        let mut records = Self::default();
+        for token in tokens {
+            records.push(token);
+        }
+        records
+    }
+    pub(crate) fn push(&mut self, token: &Token) {
+        let start = self.0.len();
+        self.0.resize(start + TOKEN_RECORD_SIZE, 0);
+        token.write_record(&mut self.0[start..]);
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.0.len() / TOKEN_RECORD_SIZE
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+    /// records packed by `push`, e.g. as read back from a page
+    pub(crate) fn from_vec(records: Vec<u8>) -> CorpusResult<Self> {
+        if !records.len().is_multiple_of(TOKEN_RECORD_SIZE) {
+            return Err(CorpusError::DecodingError(format!(
+                "{} bytes of token records",
+                records.len()
+            )));
+        }
+        Ok(Self(records))
+    }
+    pub(crate) fn get(&self, i: usize) -> Option<TokenRecord<'_>> {
+        self.0
+            .get(i * TOKEN_RECORD_SIZE..(i + 1) * TOKEN_RECORD_SIZE)
+            .map(TokenRecord)
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = TokenRecord<'_>> {
+        self.0.chunks_exact(TOKEN_RECORD_SIZE).map(TokenRecord)
+    }
+}
+
+/// a token read straight out of its record, without decoding
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TokenRecord<'a>(&'a [u8]);
+
+impl<'a> TokenRecord<'a> {
+    fn view(&self) -> token_record::View<&'a [u8]> {
+        token_record::View::new(self.0)
+    }
+    pub(crate) fn id(&self) -> u128 {
+        u128_id(token_record::id::data(self.0))
+    }
+    pub(crate) fn document_id(&self) -> u128 {
+        u128_id(token_record::document_id::data(self.0))
+    }
+    pub(crate) fn author_id(&self) -> u128 {
+        u128_id(token_record::author_id::data(self.0))
+    }
+    pub(crate) fn line(&self) -> u64 {
+        self.view().line().read()
+    }
+    pub(crate) fn position(&self) -> u64 {
+        self.view().position().read()
+    }
+    pub(crate) fn text(&self) -> StringRef {
+        let view = self.view();
+        StringRef::new(view.text_start().read(), view.text_length().read())
+    }
+    pub(crate) fn labels(&self) -> u128 {
+        u128::from_be_bytes(*token_record::labels::data(self.0))
+    }
+    pub(crate) fn to_token(self) -> Token {
+        Token {
+            id: *token_record::id::data(self.0),
+            document_id: *token_record::document_id::data(self.0),
+            author_id: *token_record::author_id::data(self.0),
+            line: self.line(),
+            position: self.position(),
+            text: self.text(),
+            labels: *token_record::labels::data(self.0),
+        }
+    }
 }
 
 impl HasId for Token {
@@ -66,6 +228,63 @@ pub struct HydratedToken {
     labels: Vec<u8>,
 }
 
+impl HydratedToken {
+    pub fn new(
+        id: u128,
+        document_id: u128,
+        author_id: u128,
+        line: u64,
+        position: u64,
+        text: String,
+        labels: u128,
+    ) -> Self {
+        Self {
+            id,
+            document_id,
+            author_id,
+            line,
+            position,
+            text,
+            labels: Vec::from(labels.to_be_bytes()),
+        }
+    }
+    pub fn document_id(&self) -> u128 {
+        self.document_id
+    }
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    pub fn author_id(&self) -> u128 {
+        self.author_id
+    }
+    /// the same token under `id`, referring to `document_id` and
+    /// `author_id` instead
+    pub(crate) fn remapped(&self, id: u128, document_id: u128, author_id: u128) -> Self {
+        Self {
+            id,
+            document_id,
+            author_id,
+            ..self.clone()
+        }
+    }
+    pub(crate) fn dehydrate(&self, strings: &mut Strings) -> CorpusResult<CorpusEntity> {
+        let labels: [u8; 16] = self
+            .labels
+            .as_slice()
+            .try_into()
+            .map_err(|_| CorpusError::InvalidDataError(format!("labels {:?}", self.labels)))?;
+        Ok(CorpusEntity::Token(Token {
+            id: self.id.to_be_bytes(),
+            document_id: self.document_id.to_be_bytes(),
+            author_id: self.author_id.to_be_bytes(),
+            line: self.line,
+            position: self.position,
+            text: strings.intern_shared(self.text.as_bytes())?,
+            labels,
+        }))
+    }
+}
+
 impl HasId for HydratedToken {
     fn id(&self) -> u128 {
         self.id
@@ -79,3 +298,37 @@ impl HasType for HydratedToken {
 }
 
 impl HasObjId for HydratedToken {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn token_records_round_trip() -> CorpusResult<()> {
+        let mut strings = Strings::new();
+        let tokens = [(1u128, "the"), (2, "cat")]
+            .into_iter()
+            .map(|(id, text)| {
+                match HydratedToken::new(id, 7, 8, 1, id as u64, text.into(), 9)
+                    .dehydrate(&mut strings)?
+                {
+                    CorpusEntity::Token(t) => Ok(t),
+                    e => panic!("wrong entity {e:?}"),
+                }
+            })
+            .collect::<CorpusResult<Vec<Token>>>()?;
+        let records = TokenRecords::from_tokens(tokens.iter());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records.as_bytes().len(), 2 * TOKEN_RECORD_SIZE);
+        let cat = records.get(1).expect("second record");
+        assert_eq!((cat.id(), cat.document_id(), cat.author_id()), (2, 7, 8));
+        assert_eq!((cat.line(), cat.position(), cat.labels()), (1, 2, 9));
+        assert_eq!(strings.get_str(&cat.text())?, "cat");
+        assert!(records.get(2).is_none());
+        for (record, token) in records.iter().zip(tokens.iter()) {
+            let unpacked = record.to_token();
+            assert_eq!(unpacked.id(), token.id());
+            assert_eq!(unpacked.text(), token.text());
+        }
+        Ok(())
+    }
+}
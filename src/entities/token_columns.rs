@@ -0,0 +1,338 @@
+use crate::entities::token::Token;
+use crate::entities::{HasId, StringRef};
+use crate::errors::{CorpusError, CorpusResult};
+use std::collections::HashMap;
+
+/// columns in the order they're stored
+const ID: usize = 0;
+const DOCUMENT_ID: usize = 1;
+const AUTHOR_ID: usize = 2;
+const LINE: usize = 3;
+const POSITION: usize = 4;
+const TEXT: usize = 5;
+const LABELS: usize = 6;
+const COLUMNS: usize = 7;
+
+/// a page of tokens stored one field at a time: a token count and the byte
+/// length of each column, then the columns themselves, so any one column
+/// can be decoded without touching the rest. ids, lines, positions and text
+/// offsets are delta encoded; document ids, author ids and labels are
+/// dictionary encoded. every number is a LEB128 varint
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TokenColumns<'a> {
+    len: usize,
+    columns: [&'a [u8]; COLUMNS],
+}
+
+impl<'a> TokenColumns<'a> {
+    pub(crate) fn encode<'t, I>(tokens: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = &'t Token>,
+    {
+        let tokens = tokens.into_iter().collect::<Vec<&Token>>();
+        let mut columns: [Vec<u8>; COLUMNS] = Default::default();
+        encode_deltas(&mut columns[ID], tokens.iter().map(|t| t.id()));
+        encode_dictionary(
+            &mut columns[DOCUMENT_ID],
+            tokens.iter().map(|t| t.document_id()),
+        );
+        encode_dictionary(
+            &mut columns[AUTHOR_ID],
+            tokens.iter().map(|t| t.author_id()),
+        );
+        encode_deltas(&mut columns[LINE], tokens.iter().map(|t| t.line() as u128));
+        encode_deltas(
+            &mut columns[POSITION],
+            tokens.iter().map(|t| t.position() as u128),
+        );
+        let mut previous = 0u64;
+        for token in tokens.iter() {
+            let text = token.text();
+            put_varint(
+                &mut columns[TEXT],
+                zigzag(text.start.wrapping_sub(previous) as i64),
+            );
+            put_varint(&mut columns[TEXT], text.length() as u128);
+            previous = text.start;
+        }
+        encode_dictionary(&mut columns[LABELS], tokens.iter().map(|t| t.labels()));
+        let mut out = Vec::new();
+        put_varint(&mut out, tokens.len() as u128);
+        for column in columns.iter() {
+            put_varint(&mut out, column.len() as u128);
+        }
+        for column in columns.iter() {
+            out.extend_from_slice(column);
+        }
+        out
+    }
+    /// split `raw` into its columns, without decoding any of them
+    pub(crate) fn new(raw: &'a [u8]) -> CorpusResult<Self> {
+        let mut cursor = Cursor { raw, pos: 0 };
+        let len = cursor.usize()?;
+        let mut lengths = [0usize; COLUMNS];
+        for length in lengths.iter_mut() {
+            *length = cursor.usize()?;
+        }
+        let mut columns: [&[u8]; COLUMNS] = [&[]; COLUMNS];
+        for (column, length) in columns.iter_mut().zip(lengths) {
+            *column = cursor.take(length)?;
+        }
+        if cursor.pos != raw.len() {
+            return Err(decoding_error("trailing bytes"));
+        }
+        Ok(Self { len, columns })
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub(crate) fn ids(&self) -> CorpusResult<Vec<u128>> {
+        decode_deltas(self.columns[ID], self.len)
+    }
+    pub(crate) fn document_ids(&self) -> CorpusResult<Vec<u128>> {
+        decode_dictionary(self.columns[DOCUMENT_ID], self.len)
+    }
+    pub(crate) fn author_ids(&self) -> CorpusResult<Vec<u128>> {
+        decode_dictionary(self.columns[AUTHOR_ID], self.len)
+    }
+    pub(crate) fn lines(&self) -> CorpusResult<Vec<u64>> {
+        narrow(decode_deltas(self.columns[LINE], self.len)?)
+    }
+    pub(crate) fn positions(&self) -> CorpusResult<Vec<u64>> {
+        narrow(decode_deltas(self.columns[POSITION], self.len)?)
+    }
+    pub(crate) fn texts(&self) -> CorpusResult<Vec<StringRef>> {
+        let mut cursor = Cursor {
+            raw: self.columns[TEXT],
+            pos: 0,
+        };
+        let mut previous = 0u64;
+        let mut texts = Vec::with_capacity(self.len.min(self.columns[TEXT].len()));
+        for _ in 0..self.len {
+            let start = previous.wrapping_add(unzigzag(cursor.varint()?) as u64);
+            texts.push(StringRef::new(start, cursor.u64()?));
+            previous = start;
+        }
+        cursor.finish(texts)
+    }
+    pub(crate) fn labels(&self) -> CorpusResult<Vec<u128>> {
+        decode_dictionary(self.columns[LABELS], self.len)
+    }
+    /// every column, reassembled into tokens
+    pub(crate) fn to_tokens(self) -> CorpusResult<Vec<Token>> {
+        let ids = self.ids()?;
+        let document_ids = self.document_ids()?;
+        let author_ids = self.author_ids()?;
+        let lines = self.lines()?;
+        let positions = self.positions()?;
+        let texts = self.texts()?;
+        let labels = self.labels()?;
+        Ok((0..self.len)
+            .map(|i| {
+                Token::new(
+                    ids[i],
+                    document_ids[i],
+                    author_ids[i],
+                    lines[i],
+                    positions[i],
+                    texts[i],
+                    labels[i],
+                )
+            })
+            .collect())
+    }
+}
+
+fn decoding_error(what: &str) -> CorpusError {
+    CorpusError::DecodingError(format!("token columns: {what}"))
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u128) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn zigzag(v: i64) -> u128 {
+    ((v << 1) ^ (v >> 63)) as u64 as u128
+}
+
+fn unzigzag(v: u128) -> i64 {
+    let v = v as u64;
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// each value as its difference from the one before. differences wrap, so
+/// values needn't be ascending
+fn encode_deltas(out: &mut Vec<u8>, values: impl Iterator<Item = u128>) {
+    let mut previous = 0u128;
+    for v in values {
+        let delta = v.wrapping_sub(previous) as i128;
+        put_varint(out, ((delta << 1) ^ (delta >> 127)) as u128);
+        previous = v;
+    }
+}
+
+fn decode_deltas(raw: &[u8], len: usize) -> CorpusResult<Vec<u128>> {
+    let mut cursor = Cursor { raw, pos: 0 };
+    let mut previous = 0u128;
+    // every value takes at least a byte, whatever the header claims
+    let mut values = Vec::with_capacity(len.min(raw.len()));
+    for _ in 0..len {
+        let zz = cursor.varint()?;
+        let delta = ((zz >> 1) as i128) ^ -((zz & 1) as i128);
+        previous = previous.wrapping_add(delta as u128);
+        values.push(previous);
+    }
+    cursor.finish(values)
+}
+
+/// the distinct values in order of first use, then each value's index
+fn encode_dictionary(out: &mut Vec<u8>, values: impl Iterator<Item = u128>) {
+    let mut codes: HashMap<u128, u128> = HashMap::new();
+    let mut distinct = Vec::new();
+    let indexes = values
+        .map(|v| {
+            *codes.entry(v).or_insert_with(|| {
+                distinct.push(v);
+                distinct.len() as u128 - 1
+            })
+        })
+        .collect::<Vec<u128>>();
+    put_varint(out, distinct.len() as u128);
+    for v in distinct {
+        put_varint(out, v);
+    }
+    for i in indexes {
+        put_varint(out, i);
+    }
+}
+
+fn decode_dictionary(raw: &[u8], len: usize) -> CorpusResult<Vec<u128>> {
+    let mut cursor = Cursor { raw, pos: 0 };
+    let distinct = (0..cursor.usize()?)
+        .map(|_| cursor.varint())
+        .collect::<CorpusResult<Vec<u128>>>()?;
+    // every value takes at least a byte, whatever the header claims
+    let mut values = Vec::with_capacity(len.min(raw.len()));
+    for _ in 0..len {
+        let i = cursor.usize()?;
+        values.push(
+            *distinct
+                .get(i)
+                .ok_or_else(|| decoding_error("dictionary index out of range"))?,
+        );
+    }
+    cursor.finish(values)
+}
+
+fn narrow(values: Vec<u128>) -> CorpusResult<Vec<u64>> {
+    values
+        .into_iter()
+        .map(|v| u64::try_from(v).map_err(|_| decoding_error("value out of range")))
+        .collect()
+}
+
+struct Cursor<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn varint(&mut self) -> CorpusResult<u128> {
+        let mut v = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = *self
+                .raw
+                .get(self.pos)
+                .ok_or_else(|| decoding_error("truncated"))?;
+            self.pos += 1;
+            v |= ((byte & 0x7f) as u128) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(decoding_error("varint too long"))
+    }
+    fn u64(&mut self) -> CorpusResult<u64> {
+        u64::try_from(self.varint()?).map_err(|_| decoding_error("value out of range"))
+    }
+    fn usize(&mut self) -> CorpusResult<usize> {
+        usize::try_from(self.varint()?).map_err(|_| decoding_error("value out of range"))
+    }
+    fn take(&mut self, n: usize) -> CorpusResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.raw.len())
+            .ok_or_else(|| decoding_error("truncated"))?;
+        let taken = &self.raw[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+    /// `values`, as long as the whole column was used up producing them
+    fn finish<T>(self, values: T) -> CorpusResult<T> {
+        if self.pos == self.raw.len() {
+            Ok(values)
+        } else {
+            Err(decoding_error("trailing bytes in column"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn token_columns_round_trip() -> CorpusResult<()> {
+        let tokens = (0..50u64)
+            .map(|i| {
+                Token::new(
+                    (3 << 64) | (i as u128 + 1),
+                    1 + (i / 20) as u128,
+                    7,
+                    i / 10,
+                    i,
+                    StringRef::new(if i % 2 == 0 { i * 3 } else { 0 }, 3),
+                    if i % 3 == 0 { u128::MAX } else { 5 },
+                )
+            })
+            .chain([Token::new(
+                3 << 64,
+                1,
+                7,
+                0,
+                0,
+                StringRef::new(0x8000_0000_0000_0002, 4),
+                0,
+            )])
+            .collect::<Vec<Token>>();
+        let raw = TokenColumns::encode(tokens.iter());
+        // around 9 bytes a token, against 112 as records
+        assert!(raw.len() < tokens.len() * 12);
+        let columns = TokenColumns::new(&raw)?;
+        assert_eq!(columns.len(), tokens.len());
+        assert_eq!(
+            columns.positions()?,
+            tokens.iter().map(|t| t.position()).collect::<Vec<u64>>()
+        );
+        assert_eq!(
+            columns.labels()?,
+            tokens.iter().map(|t| t.labels()).collect::<Vec<u128>>()
+        );
+        for (decoded, token) in columns.to_tokens()?.iter().zip(tokens.iter()) {
+            assert_eq!(decoded.id(), token.id());
+            assert_eq!(decoded.document_id(), token.document_id());
+            assert_eq!(decoded.author_id(), token.author_id());
+            assert_eq!(decoded.line(), token.line());
+            assert_eq!(decoded.text(), token.text());
+        }
+        assert!(TokenColumns::new(&raw[..raw.len() - 1]).is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
+use crate::entities::ObjType;
 use thiserror::Error;
 
 pub type CorpusResult<T> = Result<T, CorpusError>;
 
+// every variant is named for the error it is, suffix and all
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum CorpusError {
     #[error("Error accessing backing storage")]
@@ -16,16 +19,34 @@ pub enum CorpusError {
     EntityNotFoundError((u64, u64)),
     #[error("Lock error: {0}")]
     LockError(String),
+    #[error("Corpus at {0} is already open, for writing or by another process")]
+    WriterLockedError(std::path::PathBuf),
     #[error("{0} id overflow")]
     IdOverflowError(String),
+    #[error("Corpus can't be opened by this build: {0}")]
+    IncompatibleFormatError(String),
     #[error("Invalid data {0}")]
     InvalidDataError(String),
     #[error("Invalid entity type")]
     InvalidEntityTypeError,
+    #[error("Entity {0:?} refers to missing {1:?} {2}")]
+    MissingReferenceError((u64, u64), ObjType, u128),
+    #[error("Corpus needs migrating: {0}")]
+    MigrationRequiredError(String),
     #[error("Page {0} not found")]
     PageNotFoundError(u64),
+    #[error("Page {0} would hold {1} entities in {2} bytes, more than the paging policy allows")]
+    PageFullError(u64, usize, usize),
+    #[error("Snapshot of generation {0} is stale, the corpus is at {1}")]
+    StaleSnapshotError(u64, u64),
+    #[error("Subcorpus {0} not found")]
+    SubcorpusNotFoundError(String),
+    #[error("Entity {0:?} is still referred to by {1:?}")]
+    StillReferencedError((u64, u64), (u64, u64)),
     #[error("String not found between {0} and {1}")]
     StringNotFoundError(u64, u64),
+    #[error("String {0}..{1} is out of bounds for {2} bytes")]
+    StringOutOfBoundsError(usize, usize, usize),
     #[error("Invalid string found between {0} and {1}")]
     InvalidStringError(usize, usize),
 }
@@ -1,5 +1,6 @@
 use super::Labels;
 use enum_iterator::{all, Sequence};
+use std::str::FromStr;
 
 #[repr(u128)]
 #[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Sequence)]
@@ -43,8 +44,60 @@ pub enum PosLbls {
     PosWRB = 0xFF30_0000_0000_0000,
 }
 
+impl PosLbls {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ZERO => "ZERO",
+            Self::PosCC => "CC",
+            Self::PosCD => "CD",
+            Self::PosDT => "DT",
+            Self::PosEX => "EX",
+            Self::PosFW => "FW",
+            Self::PosIN => "IN",
+            Self::PosJJ => "JJ",
+            Self::PosJJR => "JJR",
+            Self::PosJS => "JJS",
+            Self::PosMD => "MD",
+            Self::PosNN => "NN",
+            Self::PosNNP => "NNP",
+            Self::PosNNPS => "NNPS",
+            Self::PosNNS => "NNS",
+            Self::PosPDT => "PDT",
+            Self::PosPOS => "POS",
+            Self::PosPRP => "PRP",
+            Self::PosRB => "RB",
+            Self::PosRBR => "RBR",
+            Self::PosRBS => "RBS",
+            Self::PosRP => "RP",
+            Self::PosSYM => "SYM",
+            Self::PosTO => "TO",
+            Self::PosUH => "UH",
+            Self::PosVB => "VB",
+            Self::PosVBD => "VBD",
+            Self::PosVBG => "VBG",
+            Self::PosVBN => "VBN",
+            Self::PosVBP => "VBP",
+            Self::PosVBZ => "VBZ",
+            Self::PosWDT => "WDT",
+            Self::PosWP => "WP",
+            Self::PosWRB => "WRB",
+        }
+    }
+}
+
 pub struct PosLabels {}
 
+impl PosLabels {
+    /// the most specific tag whose bits are all set in `val`
+    pub fn tag(&self, val: u128) -> PosLbls {
+        self.deserialize(val)
+            .unwrap_or_default()
+            .into_iter()
+            .max()
+            .unwrap_or(PosLbls::ZERO)
+    }
+}
+
 impl Labels for PosLabels {
     type Lbls = PosLbls;
 
@@ -69,3 +122,68 @@ impl Labels for PosLabels {
             }))
     }
 }
+
+/// a sequence of slots, each matching any one of its tags. parses from
+/// strings like `ADJ+NOUN` or `DT+JJ+NN`; `ADJ`, `NOUN`, `VERB` and `ADV`
+/// stand for every tag of that class
+#[derive(Clone, Debug, PartialEq)]
+pub struct PosPattern(Vec<Vec<PosLbls>>);
+
+impl PosPattern {
+    pub fn new(slots: Vec<Vec<PosLbls>>) -> Self {
+        Self(slots)
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// whether each of `labels` (as stored in `Token.labels`) fills the
+    /// corresponding slot
+    pub fn matches(&self, labels: &[u128]) -> bool {
+        let pos = PosLabels {};
+        labels.len() == self.0.len()
+            && self
+                .0
+                .iter()
+                .zip(labels.iter())
+                .all(|(slot, l)| slot.contains(&pos.tag(*l)))
+    }
+}
+
+impl FromStr for PosPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split('+')
+            .map(|slot| pattern_slot(slot.trim()))
+            .collect::<Result<Vec<Vec<PosLbls>>, String>>()
+            .map(Self)
+    }
+}
+
+fn pattern_slot(name: &str) -> Result<Vec<PosLbls>, String> {
+    match name {
+        "ADJ" => Ok(vec![PosLbls::PosJJ, PosLbls::PosJJR, PosLbls::PosJS]),
+        "NOUN" => Ok(vec![
+            PosLbls::PosNN,
+            PosLbls::PosNNS,
+            PosLbls::PosNNP,
+            PosLbls::PosNNPS,
+        ]),
+        "VERB" => Ok(vec![
+            PosLbls::PosVB,
+            PosLbls::PosVBD,
+            PosLbls::PosVBG,
+            PosLbls::PosVBN,
+            PosLbls::PosVBP,
+            PosLbls::PosVBZ,
+        ]),
+        "ADV" => Ok(vec![PosLbls::PosRB, PosLbls::PosRBR, PosLbls::PosRBS]),
+        _ => all::<PosLbls>()
+            .find(|p| p.name() == name)
+            .map(|p| vec![p])
+            .ok_or(format!("unknown POS tag {name}")),
+    }
+}
@@ -2,6 +2,8 @@
 #![feature(repr128)]
 #![feature(return_position_impl_trait_in_trait)]
 #![feature(lazy_cell)]
+pub(crate) mod analysis;
+pub mod config;
 pub(crate) mod entities;
 pub(crate) mod errors;
 pub mod labels;
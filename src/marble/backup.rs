@@ -0,0 +1,343 @@
+use crate::config::CorpusConfig;
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::manifest::{check_format, MANIFEST_ID};
+use crate::marble::read::ReadState;
+use crate::marble::write::WriteState;
+use crate::marble::{
+    counts_page_id, read_generation, strings_page_id, ArtifactDirectory, CorpusState,
+    PageDirectory, ARTIFACT_DIRECTORY_ID, PAGE_DIRECTORY_ID, RESERVED_PAGE_FLAG,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, Write};
+
+/// starts every backup archive, followed by a version byte
+const BACKUP_MAGIC: &[u8; 8] = b"CORPUSBK";
+const BACKUP_VERSION: u8 = 1;
+/// never a marble id, so it can mark the end of an archive
+const END_OF_ARCHIVE: u64 = u64::MAX;
+/// objects restored per batch
+const RESTORE_BATCH: usize = 256;
+/// copies of the store tried without a lock before writes are held off
+const BACKUP_ATTEMPTS: usize = 3;
+/// where a backup is staged in the corpus directory before it's archived
+const BACKUP_SPILL_FILE: &str = "backup.spill";
+
+fn archive_error(what: &str) -> CorpusError {
+    CorpusError::DecodingError(format!("backup archive: {what}"))
+}
+
+fn put_record<W: Write>(out: &mut W, id: u64, bytes: &[u8]) -> CorpusResult<()> {
+    out.write_all(&id.to_be_bytes())?;
+    out.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+/// the next (id, bytes) in `archive`, or `None` at its end
+fn next_record<R: Read>(archive: &mut R) -> CorpusResult<Option<(u64, Vec<u8>)>> {
+    let mut word = [0u8; 8];
+    let mut read_word = |archive: &mut R| -> CorpusResult<u64> {
+        archive.read_exact(&mut word).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => archive_error("truncated"),
+            _ => CorpusError::BackingStorageError(e),
+        })?;
+        Ok(u64::from_be_bytes(word))
+    };
+    let id = read_word(archive)?;
+    let len = read_word(archive)?;
+    if id == END_OF_ARCHIVE {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    archive.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(archive_error("truncated"));
+    }
+    Ok(Some((id, bytes)))
+}
+
+/// every page, strings page, page counts, artifact and piece of corpus
+/// metadata in `db`, written to `out` in archive order. each directory is
+/// read once, and that copy is the one archived, so the archive lists
+/// exactly the pages and artifacts it holds
+fn copy_records<W: Write>(db: &marble::Marble, out: &mut W) -> CorpusResult<()> {
+    let pages = db.read(PAGE_DIRECTORY_ID)?.map(|raw| raw.to_vec());
+    let artifacts = db.read(ARTIFACT_DIRECTORY_ID)?.map(|raw| raw.to_vec());
+    let mut ids = Vec::new();
+    if let Some(raw) = pages.as_ref() {
+        for page_id in PageDirectory::from_bytes(raw)?.0 {
+            ids.extend([page_id, strings_page_id(page_id), counts_page_id(page_id)]);
+        }
+    }
+    if let Some(raw) = artifacts.as_ref() {
+        ids.extend(ArtifactDirectory::from_bytes(raw)?.ids.into_values());
+    }
+    for id in ids {
+        if let Some(raw) = db.read(id)? {
+            put_record(out, id, &raw)?;
+        }
+    }
+    // every metadata slot sits between the page directory and the
+    // manifest
+    for id in RESERVED_PAGE_FLAG..=MANIFEST_ID {
+        let raw = match id {
+            PAGE_DIRECTORY_ID => pages.clone(),
+            ARTIFACT_DIRECTORY_ID => artifacts.clone(),
+            _ => db.read(id)?.map(|raw| raw.to_vec()),
+        };
+        if let Some(raw) = raw {
+            put_record(out, id, &raw)?;
+        }
+    }
+    Ok(())
+}
+
+/// replace whatever `spill` holds with the records of `db`
+fn spill_records(db: &marble::Marble, spill: &mut File) -> CorpusResult<()> {
+    spill.set_len(0)?;
+    spill.rewind()?;
+    let mut out = BufWriter::new(spill);
+    copy_records(db, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// changes whenever anything that goes into an archive does: the
+/// generation moves on with every entity write, and the artifact directory
+/// with every artifact saved
+fn store_version(db: &marble::Marble) -> CorpusResult<(u64, Option<Vec<u8>>)> {
+    Ok((
+        read_generation(db)?,
+        db.read(ARTIFACT_DIRECTORY_ID)?.map(|raw| raw.to_vec()),
+    ))
+}
+
+impl CorpusState<WriteState> {
+    /// write every page, strings page, page counts, artifact and piece of
+    /// corpus metadata into `out`, as one archive of a single generation,
+    /// which is returned. the store is copied into a spill file in the
+    /// corpus directory without holding any lock, and copied again if
+    /// anything is written meanwhile. only if writes keep getting in the
+    /// way are they held off while it's copied. nothing is locked while
+    /// the spill file is copied to `out`, and no more than one record is
+    /// held in memory at a time
+    pub(crate) fn backup<W: Write>(&self, mut out: W) -> CorpusResult<u64> {
+        let db = self.with_db(|db| {
+            check_format(db)?;
+            Ok(db.clone())
+        })?;
+        let spill_path = self.path()?.join(BACKUP_SPILL_FILE);
+        let mut spill = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&spill_path)?;
+        let written = self.spill_backup(&db, &mut spill).and_then(|generation| {
+            out.write_all(BACKUP_MAGIC)?;
+            out.write_all(&[BACKUP_VERSION])?;
+            spill.rewind()?;
+            io::copy(&mut BufReader::new(&mut spill), &mut out)?;
+            put_record(&mut out, END_OF_ARCHIVE, &[])?;
+            out.flush()?;
+            Ok(generation)
+        });
+        drop(spill);
+        fs::remove_file(&spill_path)?;
+        written
+    }
+    /// copy the records of one generation into `spill`, returning it
+    fn spill_backup(&self, db: &marble::Marble, spill: &mut File) -> CorpusResult<u64> {
+        for _ in 0..BACKUP_ATTEMPTS {
+            let version = store_version(db)?;
+            spill_records(db, spill)?;
+            if store_version(db)? == version {
+                return Ok(version.0);
+            }
+        }
+        self.with_db(|db| {
+            spill_records(db, spill)?;
+            read_generation(db)
+        })
+    }
+    /// build a corpus at `config.path` from an archive written by `backup`,
+    /// and open it for writing. there mustn't be a corpus there already.
+    /// metadata is written last, so a restore that fails partway leaves an
+    /// empty corpus behind
+    pub(crate) fn restore<R: Read>(config: CorpusConfig, mut archive: R) -> CorpusResult<Self> {
+        let mut header = [0u8; 9];
+        archive
+            .read_exact(&mut header)
+            .map_err(|_| archive_error("not a corpus backup"))?;
+        if &header[..8] != BACKUP_MAGIC {
+            return Err(archive_error("not a corpus backup"));
+        }
+        if header[8] != BACKUP_VERSION {
+            return Err(archive_error(&format!("unknown version {}", header[8])));
+        }
+        let writer = Self::new(config.clone())?;
+        let occupied = writer
+            .with_db(|db| Ok(db.read(PAGE_DIRECTORY_ID)?.is_some() || read_generation(db)? != 0))?;
+        if occupied {
+            return Err(CorpusError::ConfigurationError(format!(
+                "can't restore into {}, it already holds a corpus",
+                config.path.display()
+            )));
+        }
+        let mut batch = Vec::new();
+        let mut metadata = Vec::new();
+        while let Some((id, bytes)) = next_record(&mut archive)? {
+            if (RESERVED_PAGE_FLAG..=MANIFEST_ID).contains(&id) {
+                metadata.push((id, Some(bytes)));
+                continue;
+            }
+            batch.push((id, Some(bytes)));
+            if batch.len() == RESTORE_BATCH {
+                writer.load_raw(std::mem::take(&mut batch))?;
+            }
+        }
+        writer.load_raw(batch)?;
+        // keeps the generation the archive was taken at
+        writer.load_raw(metadata)?;
+        // reopened so the restored manifest is checked and loaded
+        drop(writer);
+        Self::new(config)
+    }
+}
+
+impl CorpusState<ReadState> {
+    /// restore an archive written by `backup` to `config.path` and open it
+    /// for reading. this is how another process reads a corpus while its
+    /// writer stays open
+    pub(crate) fn open_backup<R: Read>(config: CorpusConfig, archive: R) -> CorpusResult<Self> {
+        drop(CorpusState::<WriteState>::restore(config.clone(), archive)?);
+        Self::new(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::HydratedEntity;
+    use crate::marble::{_test_config, _test_document, CorpusHydrate, CorpusRead, CorpusWrite};
+
+    #[test]
+    fn backup_and_restore() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("backup"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        writer.write_artifact("stats", vec![1, 2, 3], writer.with_db(read_generation)?)?;
+        let mut archive = Vec::new();
+        let generation = writer.backup(&mut archive)?;
+        let artifacts = writer.read_reserved(ARTIFACT_DIRECTORY_ID)?;
+        assert_eq!(generation, writer.reader()?.generation()?);
+        // writes after the backup aren't in it
+        writer.write_objs(_test_document(4, 2, 3))?;
+
+        let config = _test_config("restore");
+        let restored = CorpusState::<WriteState>::restore(config.clone(), archive.as_slice())?;
+        assert_eq!(restored.page_ids()?, vec![0]);
+        assert_eq!(restored.read_reserved(ARTIFACT_DIRECTORY_ID)?, artifacts);
+        assert!(restored.verify(false)?.problems.is_empty());
+        let reader = restored.reader()?;
+        assert_eq!(reader.generation()?, generation);
+        match reader.hydrate_obj(&reader.read_obj(2u128.to_be_bytes())?)? {
+            HydratedEntity::Author(a) => assert_eq!(a.name(), "a"),
+            e => panic!("wrong entity {e:?}"),
+        }
+        assert_eq!(restored.stored_page(0)?.expect("page").0.len(), 3);
+        drop(reader);
+        match CorpusState::<WriteState>::restore(config.clone(), archive.as_slice()) {
+            Err(CorpusError::WriterLockedError(_)) => (),
+            r => panic!("restored over an open corpus: {:?}", r.map(|_| ())),
+        }
+        drop(restored);
+        match CorpusState::<WriteState>::restore(config, archive.as_slice()) {
+            Err(CorpusError::ConfigurationError(_)) => (),
+            r => panic!("restored over a corpus: {:?}", r.map(|_| ())),
+        }
+        match CorpusState::<WriteState>::restore(
+            _test_config("restore-truncated"),
+            &archive[..archive.len() - 4],
+        ) {
+            Err(CorpusError::DecodingError(_)) => (),
+            r => panic!("restored a truncated archive: {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_backup_of_open_corpus() -> CorpusResult<()> {
+        let config = _test_config("backup-open");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        match CorpusState::<ReadState>::new(config) {
+            Err(CorpusError::WriterLockedError(_)) => (),
+            r => panic!("opened a reader next to a writer: {:?}", r.map(|_| ())),
+        }
+        let mut archive = Vec::new();
+        writer.backup(&mut archive)?;
+        let reader = CorpusState::<ReadState>::open_backup(
+            _test_config("backup-open-copy"),
+            archive.as_slice(),
+        )?;
+        assert_eq!(reader.page_ids()?, vec![0]);
+        match reader.hydrate_obj(&reader.read_obj(2u128.to_be_bytes())?)? {
+            HydratedEntity::Author(a) => assert_eq!(a.name(), "a"),
+            e => panic!("wrong entity {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn artifact_saves_change_store_version() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("backup-version"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        let generation = writer.with_db(read_generation)?;
+        writer.write_artifact("stats", vec![1], generation)?;
+        let version = writer.with_db(store_version)?;
+        // the same artifact at the same generation, with different bytes
+        writer.write_artifact("stats", vec![2], generation)?;
+        assert_ne!(writer.with_db(store_version)?, version);
+        let mut archive = Vec::new();
+        assert_eq!(writer.backup(&mut archive)?, generation);
+        assert!(!writer.path()?.join(BACKUP_SPILL_FILE).exists());
+        Ok(())
+    }
+
+    /// records whether the writer could have committed during each write
+    struct Probe<'a> {
+        writer: &'a CorpusState<WriteState>,
+        unlocked: bool,
+        bytes: Vec<u8>,
+    }
+
+    impl Write for Probe<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.unlocked &= self.writer.lock().try_write().is_ok();
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backup_writes_unlocked() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("backup-unlocked"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        let mut probe = Probe {
+            writer: &writer,
+            unlocked: true,
+            bytes: Vec::new(),
+        };
+        writer.backup(&mut probe)?;
+        assert!(probe.unlocked);
+        let restored = CorpusState::<WriteState>::restore(
+            _test_config("backup-unlocked-copy"),
+            probe.bytes.as_slice(),
+        )?;
+        assert_eq!(restored.page_ids()?, vec![0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// bytes currently held, by the sizes reported on insert
+    pub bytes: usize,
+    pub entries: usize,
+}
+
+/// least-recently-used cache that holds at most `budget` bytes. sizes are
+/// whatever callers report on insert
+#[derive(Debug)]
+pub(crate) struct LruCache<K, V> {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<K, (V, usize, u64)>,
+    /// last use -> key, oldest first
+    recency: BTreeMap<u64, K>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some((value, _, last)) => {
+                self.recency.remove(last);
+                self.recency.insert(self.tick, key.clone());
+                *last = self.tick;
+                self.hits += 1;
+                Some(value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+    /// values bigger than the whole budget aren't kept
+    pub(crate) fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.budget {
+            return;
+        }
+        self.tick += 1;
+        self.used += size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        self.evict();
+    }
+    fn remove(&mut self, key: &K) {
+        if let Some((_, size, last)) = self.entries.remove(key) {
+            self.recency.remove(&last);
+            self.used -= size;
+        }
+    }
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&key) {
+                self.used -= size;
+                self.evictions += 1;
+            }
+        }
+    }
+    pub(crate) fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+    /// drop every entry, keeping the counters
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.used = 0;
+    }
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            bytes: self.used,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn lru_eviction() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, "a", 4);
+        cache.insert(2, "b", 4);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.insert(3, "c", 4);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&3), Some(&"c"));
+        cache.insert(4, "d", 11);
+        assert_eq!(cache.get(&4), None);
+        cache.set_budget(4);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 3,
+                evictions: 2,
+                bytes: 4,
+                entries: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::entities::{obj_id, parse_obj_id, HasReferences, ObjType};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::transaction::Pending;
+use crate::marble::write::WriteState;
+use crate::marble::{counts_page_id, Page, PageCounts};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+
+/// what happens when an author or collection that something still refers to
+/// is deleted. deleting a document always takes its tokens with it
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DeletePolicy {
+    /// fail with `StillReferencedError`
+    #[default]
+    Reject,
+    /// delete whatever refers to it as well
+    Cascade,
+}
+
+impl WriteState {
+    /// every entity a transaction leaves in place must refer only to
+    /// entities it also leaves in place, or that are stored and it doesn't
+    /// delete. run under the write lock, so nothing can commit in between
+    pub(crate) fn check_references(&self, pending: &Pending) -> CorpusResult<()> {
+        let mut pages: HashMap<u64, Option<Page>> = HashMap::new();
+        for (referrer, obj) in pending.present.iter() {
+            for (t, id) in obj.references() {
+                let target = obj_id(id, t);
+                if pending.present.contains_key(&target) {
+                    continue;
+                }
+                let page = match pages.entry(target.0) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(self.stored_page(target.0)?),
+                };
+                let stored = page.as_ref().is_some_and(|p| p.0.contains_key(&target.1));
+                if !stored || pending.removed.contains(&target) {
+                    return Err(CorpusError::MissingReferenceError(*referrer, t, id));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// `obj_ids` plus everything that has to go with them: the tokens of
+    /// deleted documents, and under `DeletePolicy::Cascade` whatever refers
+    /// to deleted authors and collections. `pending` stands in for the
+    /// stored versions of whatever it touches. run under the write lock,
+    /// so nothing can refer to what's deleted by the time it is
+    pub(crate) fn cascade_deletes(
+        &self,
+        obj_ids: &[(u64, u64)],
+        pending: &Pending,
+    ) -> CorpusResult<Vec<(u64, u64)>> {
+        let mut deleting = obj_ids.iter().copied().collect::<BTreeSet<(u64, u64)>>();
+        let referable = deleting.iter().any(|(_, key)| {
+            matches!(
+                parse_obj_id(*key),
+                Ok(ObjType::Author | ObjType::Collection | ObjType::Document)
+            )
+        });
+        if !referable {
+            return Ok(deleting.into_iter().collect());
+        }
+        let policy = self.delete_policy;
+        // what each page refers to, from its counts. pages without them are
+        // always read
+        let mut pages = Vec::new();
+        for page_id in self.page_ids()? {
+            let references = match self.db().read(counts_page_id(page_id))? {
+                Some(raw) => PageCounts::from_bytes(&raw)?.references,
+                None => None,
+            };
+            pages.push((page_id, references));
+        }
+        let follow = |deleting: &mut BTreeSet<(u64, u64)>,
+                      referrer: (u64, u64),
+                      references: Vec<(ObjType, u128)>| {
+            if deleting.contains(&referrer) {
+                return Ok(());
+            }
+            for (t, id) in references {
+                let target = obj_id(id, t);
+                if !deleting.contains(&target) {
+                    continue;
+                }
+                if t != ObjType::Document && policy == DeletePolicy::Reject {
+                    return Err(CorpusError::StillReferencedError(target, referrer));
+                }
+                deleting.insert(referrer);
+                break;
+            }
+            Ok(())
+        };
+        // documents first, so their tokens are found in the second pass
+        for pass in [ObjType::Document, ObjType::Token] {
+            let targets = match pass {
+                ObjType::Document => [ObjType::Author, ObjType::Collection].as_slice(),
+                _ => [ObjType::Document].as_slice(),
+            };
+            let deleting_targets = deleting
+                .iter()
+                .any(|(_, key)| parse_obj_id(*key).is_ok_and(|t| targets.contains(&t)));
+            if !deleting_targets {
+                continue;
+            }
+            for (page_id, references) in pages.iter() {
+                let refers = references.as_ref().is_none_or(|references| {
+                    references.keys().any(|target| deleting.contains(target))
+                });
+                if !refers {
+                    continue;
+                }
+                let Some(page) = self.stored_page(*page_id)? else {
+                    continue;
+                };
+                for (key, entity) in page.0.iter() {
+                    let referrer = (*page_id, *key);
+                    if entity.obj_type() != pass
+                        || pending.present.contains_key(&referrer)
+                        || pending.removed.contains(&referrer)
+                    {
+                        continue;
+                    }
+                    follow(&mut deleting, referrer, entity.references())?;
+                }
+            }
+            for (referrer, obj) in pending.present.iter() {
+                if obj.obj_type() == pass {
+                    follow(&mut deleting, *referrer, obj.references())?;
+                }
+            }
+        }
+        Ok(deleting.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marble::{_test_config, _test_document, _test_token, CorpusState, CorpusWrite};
+
+    #[test]
+    fn missing_references_rejected() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("missing-reference"))?;
+        match writer.write_objs(vec![_test_token(1, 1, 2)]) {
+            Err(CorpusError::MissingReferenceError(_, ObjType::Document, 1)) => (),
+            r => panic!("dangling token written: {r:?}"),
+        }
+        let mut objs = _test_document(1, 2, 3);
+        objs.push(_test_token(1, 1, 2));
+        writer.write_objs(objs)?;
+        match writer.update_objs(vec![_test_token(1, 1, 4)]) {
+            Err(CorpusError::MissingReferenceError(_, ObjType::Author, 4)) => (),
+            r => panic!("dangling token updated: {r:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_cascade() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("cascade"))?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.extend(_test_document(4, 2, 3));
+        objs.extend([
+            _test_token(1, 1, 2),
+            _test_token(2, 1, 2),
+            _test_token(3, 4, 2),
+        ]);
+        writer.write_objs(objs)?;
+        writer.delete_objs(vec![obj_id(1, ObjType::Document)])?;
+        let tokens = writer.stored_page(1)?.expect("token page");
+        assert_eq!(tokens.0.len(), 1);
+        match writer.delete_objs(vec![obj_id(2, ObjType::Author)]) {
+            Err(CorpusError::StillReferencedError(..)) => (),
+            r => panic!("referenced author deleted: {r:?}"),
+        }
+        writer.set_delete_policy(DeletePolicy::Cascade)?;
+        writer.delete_objs(vec![obj_id(2, ObjType::Author)])?;
+        assert_eq!(writer.page_ids()?, vec![0]);
+        let page = writer.stored_page(0)?.expect("collection page");
+        assert_eq!(
+            page.0.values().map(|e| e.obj_type()).collect::<Vec<_>>(),
+            vec![ObjType::Collection]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cascades_read_referring_pages() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("cascade-counts"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        writer.write_objs(vec![_test_token(1, 1, 2), _test_token(2, 1, 2)])?;
+        let counts = |page_id| -> CorpusResult<PageCounts> {
+            PageCounts::from_bytes(
+                &writer
+                    .read_reserved(counts_page_id(page_id))?
+                    .expect("counts"),
+            )
+        };
+        let references = counts(1)?.references.expect("references");
+        assert_eq!(references.get(&obj_id(1, ObjType::Document)), Some(&2));
+        assert_eq!(references.get(&obj_id(2, ObjType::Author)), Some(&2));
+        // counts from before references were kept still have their page read
+        let legacy = PageCounts {
+            references: None,
+            ..counts(1)?
+        };
+        writer.write_raw(vec![(counts_page_id(1), Some(legacy.to_bytes()?))])?;
+        writer.delete_objs(vec![obj_id(1, ObjType::Document)])?;
+        assert!(writer.stored_page(1)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use crate::entities::dictionary::Dictionary;
+use crate::entities::strings::Strings;
+use crate::entities::CorpusEntity;
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::write::WriteState;
+use crate::marble::{decode_dictionary, strings_page_id, CorpusState, Page, DICTIONARY_ID};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+impl CorpusState<WriteState> {
+    /// drop the dead bytes from every strings page, one page at a time.
+    /// each page is read, compacted and written back under the write lock,
+    /// so nothing committed to it meanwhile is lost. returns the number of
+    /// bytes reclaimed
+    pub(crate) fn compact_strings(&self) -> CorpusResult<u64> {
+        self.check_format()?;
+        let format = self.page_format()?;
+        let mut reclaimed = 0u64;
+        for page_id in self.page_ids()? {
+            reclaimed += self.update_raw(|st| {
+                let Some(mut page) = st.stored_page(page_id)? else {
+                    return Ok((vec![], 0));
+                };
+                let strings = match st.db().read(strings_page_id(page_id))? {
+                    Some(raw) => Strings::from_bytes(&raw),
+                    None => Strings::new(),
+                };
+                let compacted = page.compact_strings(&strings)?;
+                let before = strings.as_bytes().len() as u64;
+                let after = compacted.as_bytes().len() as u64;
+                if after == before {
+                    return Ok((vec![], 0));
+                }
+                Ok((
+                    vec![
+                        (page_id, Some(page.encode(format)?)),
+                        (
+                            strings_page_id(page_id),
+                            Some(compacted.as_bytes().to_vec()),
+                        ),
+                    ],
+                    before - after,
+                ))
+            })?;
+        }
+        Ok(reclaimed)
+    }
+    /// replace the corpus dictionary with the `k` most frequent token texts
+    /// and rewrite every page against it. with `k` of 0 the dictionary is
+    /// removed. only one page is held in memory at a time: a staged
+    /// dictionary holding the old and new strings side by side is stored
+    /// first, so pages stay readable whichever one they refer to while
+    /// they're rewritten, and the old strings are dropped at the end.
+    /// readers opened before keep their dictionary until they refresh
+    pub(crate) fn build_dictionary(&self, k: usize) -> CorpusResult<usize> {
+        self.check_format()?;
+        let old = decode_dictionary(self.read_reserved(DICTIONARY_ID)?)?.unwrap_or_default();
+        let mut counts: HashMap<Vec<u8>, u64> = HashMap::new();
+        for page_id in self.page_ids()? {
+            let Some(page) = self.stored_page(page_id)? else {
+                continue;
+            };
+            let strings = self
+                .stored_strings(page_id)?
+                .with_dictionary(Some(old.clone()));
+            for entity in page.0.values() {
+                if let CorpusEntity::Token(t) = entity {
+                    *counts
+                        .entry(strings.get_bytes(&t.text())?.to_vec())
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+        let staged = Arc::new(old.staged(&Dictionary::from_counts(counts, k)));
+        let size = staged.len();
+        self.write_reserved(DICTIONARY_ID, Some(staged.to_bytes()?))?;
+        let format = self.page_format()?;
+        for page_id in self.page_ids()? {
+            self.update_raw(|st| {
+                let db = st.db();
+                let Some(raw) = db.read(page_id)? else {
+                    return Ok((vec![], ()));
+                };
+                let mut page = Page::from_bytes(&raw)
+                    .map_err(|_| CorpusError::DecodingError(format!("Decoding page {page_id}")))?;
+                let strings = match db.read(strings_page_id(page_id))? {
+                    Some(raw) => Strings::from_bytes(&raw),
+                    None => Strings::new(),
+                }
+                .with_dictionary(Some(staged.clone()));
+                let mut rewritten = Strings::new().with_dictionary(Some(staged.clone()));
+                for entity in page.0.values_mut() {
+                    *entity = entity.hydrate(&strings)?.dehydrate(&mut rewritten)?;
+                }
+                Ok((
+                    vec![
+                        (page_id, Some(page.encode(format)?)),
+                        (
+                            strings_page_id(page_id),
+                            Some(rewritten.as_bytes().to_vec()),
+                        ),
+                    ],
+                    (),
+                ))
+            })?;
+        }
+        let settled = staged.settled();
+        self.write_reserved(
+            DICTIONARY_ID,
+            (!settled.is_empty())
+                .then(|| settled.to_bytes())
+                .transpose()?,
+        )?;
+        Ok(size)
+    }
+    /// the stored strings page of `page_id`, empty if there is none
+    fn stored_strings(&self, page_id: u64) -> CorpusResult<Strings> {
+        Ok(match self.read_reserved(strings_page_id(page_id))? {
+            Some(raw) => Strings::from_bytes(&raw),
+            None => Strings::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::token::HydratedToken;
+    use crate::entities::{HydratedEntity, ObjType};
+    use crate::marble::read::ReadState;
+    use crate::marble::{_test_config, _test_document, CorpusHydrate, CorpusRead, CorpusWrite};
+
+    #[test]
+    fn shared_dictionary() -> CorpusResult<()> {
+        let config = _test_config("dictionary");
+        let words = ["the", "cat", "and", "the", "dog", "and", "the"];
+        let mut objs = _test_document(1, 1, 1);
+        objs.extend(words.iter().enumerate().map(|(i, w)| {
+            HydratedEntity::Token(HydratedToken::new(
+                (1 << 64) | i as u128,
+                1,
+                1,
+                0,
+                i as u64,
+                w.to_string(),
+                0,
+            ))
+        }));
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(objs)?;
+        assert_eq!(
+            writer.read_reserved(strings_page_id(1))?,
+            Some(b"thecatanddog".to_vec())
+        );
+        assert_eq!(writer.build_dictionary(2)?, 2);
+        assert_eq!(
+            writer.read_reserved(strings_page_id(1))?,
+            Some(b"catdog".to_vec())
+        );
+        // new tokens pick up existing codes
+        writer.write_objs(vec![HydratedEntity::Token(HydratedToken::new(
+            (1 << 64) | 9,
+            1,
+            1,
+            0,
+            9,
+            "and".into(),
+            0,
+        ))])?;
+        assert_eq!(
+            writer.read_reserved(strings_page_id(1))?,
+            Some(b"catdog".to_vec())
+        );
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config.clone())?;
+        let texts = reader
+            .read_objs(
+                [0u128, 2, 9].map(|i| ((1u128 << 64) | i | (ObjType::Token as u128)).to_be_bytes()),
+            )?
+            .iter()
+            .map(|e| match reader.hydrate_obj(e)? {
+                HydratedEntity::Token(t) => Ok(t.text().to_string()),
+                e => panic!("wrong entity {e:?}"),
+            })
+            .collect::<CorpusResult<Vec<String>>>()?;
+        assert_eq!(texts, vec!["the", "and", "and"]);
+        drop(reader);
+        let writer = CorpusState::<WriteState>::new(config)?;
+        assert_eq!(writer.build_dictionary(0)?, 0);
+        assert!(writer.read_reserved(DICTIONARY_ID)?.is_none());
+        assert_eq!(
+            writer.read_reserved(strings_page_id(1))?,
+            Some(b"thecatanddog".to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_dictionary() -> CorpusResult<()> {
+        let config = _test_config("rebuild-dictionary");
+        let words = ["the", "cat", "and", "the", "dog", "and", "the"];
+        let mut objs = _test_document(1, 1, 1);
+        objs.extend(words.iter().enumerate().map(|(i, w)| {
+            HydratedEntity::Token(HydratedToken::new(
+                (1 << 64) | i as u128,
+                1,
+                1,
+                0,
+                i as u64,
+                w.to_string(),
+                0,
+            ))
+        }));
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(objs)?;
+        assert_eq!(writer.build_dictionary(1)?, 1);
+        assert_eq!(writer.build_dictionary(3)?, 3);
+        // codes of the first dictionary are retired, not reused
+        let dictionary =
+            decode_dictionary(writer.read_reserved(DICTIONARY_ID)?)?.expect("dictionary");
+        assert_eq!(dictionary.get(0), None);
+        assert_eq!(dictionary.get(1), Some(b"the".as_slice()));
+        assert_eq!(
+            writer.read_reserved(strings_page_id(1))?,
+            Some(b"dog".to_vec())
+        );
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        let texts = reader
+            .read_objs(
+                (0u128..7)
+                    .map(|i| ((1u128 << 64) | i | (ObjType::Token as u128)).to_be_bytes())
+                    .collect::<Vec<_>>(),
+            )?
+            .iter()
+            .map(|e| match reader.hydrate_obj(e)? {
+                HydratedEntity::Token(t) => Ok(t.text().to_string()),
+                e => panic!("wrong entity {e:?}"),
+            })
+            .collect::<CorpusResult<Vec<String>>>()?;
+        assert_eq!(texts, words);
+        Ok(())
+    }
+
+    #[test]
+    fn staged_dictionary() {
+        let old = Dictionary::from_counts(HashMap::from([(b"cat".to_vec(), 1)]), 1);
+        let staged = old.staged(&Dictionary::from_counts(
+            HashMap::from([(b"dog".to_vec(), 1)]),
+            1,
+        ));
+        assert_eq!(staged.len(), 1);
+        assert_eq!(staged.get(0), Some(b"cat".as_slice()));
+        assert_eq!(staged.code(b"cat"), None);
+        assert_eq!(staged.code(b"dog"), Some(1));
+        let settled = staged.settled();
+        assert_eq!(settled.get(0), None);
+        assert_eq!(settled.code(b"dog"), Some(1));
+        assert_eq!(
+            Dictionary::from_bytes(&settled.to_bytes().unwrap()).unwrap(),
+            settled
+        );
+    }
+
+    #[test]
+    fn compact_strings_pages() -> CorpusResult<()> {
+        let config = _test_config("compact-strings");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(vec![HydratedEntity::Author(HydratedAuthor::new(
+            1,
+            "Mary".into(),
+            "notes".into(),
+        ))])?;
+        let mut raw = writer.read_reserved(strings_page_id(0))?.expect("strings");
+        raw.extend_from_slice(b"dead");
+        writer.write_raw(vec![(strings_page_id(0), Some(raw))])?;
+        assert_eq!(writer.compact_strings()?, 4);
+        assert_eq!(writer.compact_strings()?, 0);
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        assert_eq!(
+            reader.read_reserved(strings_page_id(0))?,
+            Some(b"Marynotes".to_vec())
+        );
+        match reader.hydrate_obj(&reader.read_obj(1u128.to_be_bytes())?)? {
+            HydratedEntity::Author(a) => assert_eq!(a.name(), "Mary"),
+            e => panic!("wrong entity {e:?}"),
+        }
+        Ok(())
+    }
+}
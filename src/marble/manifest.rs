@@ -0,0 +1,384 @@
+use crate::config::LABEL_SCHEMAS;
+use crate::entities::StringRef;
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::paging::Allocator;
+use crate::marble::write::WriteState;
+use crate::marble::{
+    strings_page_id, CorpusState, PAGE_DIRECTORY_ID, RESERVED_PAGE_FLAG, STRINGS_FORMAT,
+    STRINGS_FORMAT_ID,
+};
+use minicbor::{Decode, Encode};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// what's stored, and how. corpora from before it have none
+pub(crate) const MANIFEST_ID: u64 = RESERVED_PAGE_FLAG | 6;
+/// the last page a migration that goes page by page has finished, while
+/// it's under way
+const MIGRATION_PROGRESS_ID: u64 = RESERVED_PAGE_FLAG | 7;
+/// the on-disk format this build writes:
+/// 0. string references with inclusive lengths
+/// 1. half-open string references, marked by `STRINGS_FORMAT_ID`
+/// 2. a manifest
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+/// describes a corpus to whatever opens it
+#[derive(Clone, Debug, Decode, Encode, PartialEq)]
+pub(crate) struct Manifest {
+    #[n(0)]
+    pub(crate) format_version: u32,
+    /// seconds since the epoch. for migrated corpora, when they were migrated
+    #[n(1)]
+    pub(crate) created: u64,
+    /// the label schemas tokens are labelled with
+    #[n(2)]
+    pub(crate) label_schemas: Vec<String>,
+    #[n(3)]
+    pub(crate) allocator: Allocator,
+}
+
+impl Manifest {
+    pub(crate) fn new(label_schemas: Vec<String>) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            label_schemas,
+            allocator: Allocator::default(),
+        }
+    }
+    pub(crate) fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let mut v = Vec::new();
+        minicbor::encode::<&Manifest, &mut Vec<u8>>(self, v.as_mut())
+            .map_err(|_| CorpusError::EncodingError("Manifest encoding error".to_string()))?;
+        Ok(v)
+    }
+    pub(crate) fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        minicbor::decode::<Manifest>(raw)
+            .map_err(|_| CorpusError::DecodingError("loading manifest".to_string()))
+    }
+}
+
+/// the manifest stored in `db`, if there is one
+pub(crate) fn read_manifest(db: &marble::Marble) -> CorpusResult<Option<Manifest>> {
+    db.read(MANIFEST_ID)?
+        .map(|raw| Manifest::from_bytes(&raw))
+        .transpose()
+}
+
+/// the format version of `db`; `None` if nothing has been written to it
+pub(crate) fn stored_format_version(db: &marble::Marble) -> CorpusResult<Option<u32>> {
+    if let Some(manifest) = read_manifest(db)? {
+        return Ok(Some(manifest.format_version));
+    }
+    match db.read(STRINGS_FORMAT_ID)? {
+        Some(raw) if raw.as_ref() == [STRINGS_FORMAT] => Ok(Some(1)),
+        Some(raw) => Err(CorpusError::IncompatibleFormatError(format!(
+            "unknown string layout {:?}",
+            raw.as_ref()
+        ))),
+        None if db.read(PAGE_DIRECTORY_ID)?.is_none() => Ok(None),
+        None => Ok(Some(0)),
+    }
+}
+
+/// error unless `db` is empty, or in the current format with label schemas
+/// this build knows
+pub(crate) fn check_format(db: &marble::Marble) -> CorpusResult<()> {
+    match stored_format_version(db)? {
+        None => Ok(()),
+        Some(FORMAT_VERSION) => {
+            if let Some(manifest) = read_manifest(db)? {
+                for schema in manifest.label_schemas.iter() {
+                    if !LABEL_SCHEMAS.contains(&schema.as_str()) {
+                        return Err(CorpusError::IncompatibleFormatError(format!(
+                            "unknown label schema {schema:?}"
+                        )));
+                    }
+                }
+            }
+            Ok(())
+        }
+        Some(v) if v > FORMAT_VERSION => Err(CorpusError::IncompatibleFormatError(format!(
+            "format version {v} is newer than this build's {FORMAT_VERSION}"
+        ))),
+        Some(v) => Err(CorpusError::MigrationRequiredError(format!(
+            "format version {v} is older than {FORMAT_VERSION}"
+        ))),
+    }
+}
+
+/// one step up from format version `from`. each step records the new
+/// version in the last thing it writes, so an interrupted migration picks
+/// up at the step it stopped in. steps that rewrite every page do so one
+/// page at a time, recording how far they got, so they pick up at the page
+/// they stopped at
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(&CorpusState<WriteState>) -> CorpusResult<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "half-open string references",
+        apply: half_open_string_refs,
+    },
+    Migration {
+        from: 1,
+        description: "corpus manifest",
+        apply: add_manifest,
+    },
+];
+
+/// rewrite references stored with inclusive lengths (`length` one less than
+/// the byte count) to the current layout. the old layout stored the empty
+/// string as a zero-length reference starting wherever the next string was
+/// appended, the same as a one-byte string there. a zero-length reference
+/// is taken to be empty when it starts at the end of the page's strings,
+/// or where a longer reference also starts, since appended strings never
+/// share a start otherwise. an empty string followed by a one-byte string
+/// can't be told apart from that string, and becomes it. references that
+/// still don't fit are left for `verify` to report
+fn half_open_string_refs(cs: &CorpusState<WriteState>) -> CorpusResult<()> {
+    let format = cs.page_format()?;
+    let done = match cs.read_reserved(MIGRATION_PROGRESS_ID)? {
+        Some(raw) => Some(u64::from_be_bytes(raw.as_slice().try_into().map_err(
+            |_| CorpusError::DecodingError("migration progress".to_string()),
+        )?)),
+        None => None,
+    };
+    for page_id in cs.page_ids()? {
+        if done.is_some_and(|done| page_id <= done) {
+            continue;
+        }
+        cs.update_raw(|st| {
+            let Some(mut page) = st.stored_page(page_id)? else {
+                return Ok((vec![], ()));
+            };
+            let end = st
+                .db()
+                .read(strings_page_id(page_id))?
+                .map_or(0, |raw| raw.len() as u64);
+            let longer = page
+                .0
+                .values()
+                .flat_map(|entity| entity.string_refs())
+                .filter(|r| !r.is_shared() && r.length() > 0)
+                .map(|r| r.start)
+                .collect::<HashSet<u64>>();
+            for entity in page.0.values_mut() {
+                for string_ref in entity.string_refs_mut() {
+                    let empty = !string_ref.is_shared()
+                        && string_ref.length() == 0
+                        && (string_ref.start == end || longer.contains(&string_ref.start));
+                    *string_ref = if empty {
+                        StringRef::empty()
+                    } else {
+                        StringRef::new(string_ref.start, string_ref.length() + 1)
+                    };
+                }
+            }
+            Ok((
+                vec![
+                    (page_id, Some(page.encode(format)?)),
+                    (MIGRATION_PROGRESS_ID, Some(page_id.to_be_bytes().to_vec())),
+                ],
+                (),
+            ))
+        })?;
+    }
+    cs.write_raw(vec![
+        (MIGRATION_PROGRESS_ID, None),
+        (STRINGS_FORMAT_ID, Some(vec![STRINGS_FORMAT])),
+    ])
+}
+
+/// the string layout marker is folded into the manifest
+fn add_manifest(cs: &CorpusState<WriteState>) -> CorpusResult<()> {
+    let manifest = Manifest::new(cs.label_schemas()?);
+    cs.write_raw(vec![
+        (MANIFEST_ID, Some(manifest.to_bytes()?)),
+        (STRINGS_FORMAT_ID, None),
+    ])
+}
+
+impl CorpusState<WriteState> {
+    pub(crate) fn manifest(&self) -> CorpusResult<Option<Manifest>> {
+        self.read_reserved(MANIFEST_ID)?
+            .map(|raw| Manifest::from_bytes(&raw))
+            .transpose()
+    }
+    /// bring the corpus up to `FORMAT_VERSION` one step at a time. returns
+    /// the steps taken, none if it was already current
+    pub(crate) fn migrate(&self) -> CorpusResult<Vec<&'static str>> {
+        let mut applied = Vec::new();
+        loop {
+            let version = self.with_db(stored_format_version)?;
+            let Some(version) = version.filter(|v| *v < FORMAT_VERSION) else {
+                break;
+            };
+            let migration = MIGRATIONS
+                .iter()
+                .find(|m| m.from == version)
+                .ok_or_else(|| {
+                    CorpusError::IncompatibleFormatError(format!(
+                        "no migration from format version {version}"
+                    ))
+                })?;
+            (migration.apply)(self)?;
+            applied.push(migration.description);
+        }
+        self.with_db(check_format)?;
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::HydratedEntity;
+    use crate::marble::read::ReadState;
+    use crate::marble::{_test_config, CorpusHydrate, CorpusRead, CorpusWrite};
+
+    #[test]
+    fn migrate_old_layouts() -> CorpusResult<()> {
+        let config = _test_config("migrate-layouts");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        let manifest = writer.manifest()?.expect("manifest");
+        assert_eq!(manifest.format_version, FORMAT_VERSION);
+        assert_eq!(manifest.label_schemas, config.label_schemas);
+        writer.write_objs(vec![HydratedEntity::Author(HydratedAuthor::new(
+            1,
+            "Mary".into(),
+            "n".into(),
+        ))])?;
+        // put the corpus back the way the first versions stored it
+        let mut page = writer.stored_page(0)?.expect("page");
+        for entity in page.0.values_mut() {
+            for string_ref in entity.string_refs_mut() {
+                *string_ref = StringRef::new(string_ref.start, string_ref.length() - 1);
+            }
+        }
+        writer.write_raw(vec![(0, Some(page.to_bytes()?)), (MANIFEST_ID, None)])?;
+        match writer.write_objs(Vec::<HydratedEntity>::new()) {
+            Err(CorpusError::MigrationRequiredError(_)) => (),
+            r => panic!("wrote to an unmigrated corpus: {r:?}"),
+        }
+        drop(writer);
+        match CorpusState::<ReadState>::new(config.clone()) {
+            Err(CorpusError::MigrationRequiredError(_)) => (),
+            r => panic!("read an unmigrated corpus: {r:?}"),
+        }
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        assert_eq!(
+            writer.migrate()?,
+            vec!["half-open string references", "corpus manifest"]
+        );
+        assert!(writer.migrate()?.is_empty());
+        assert!(writer.read_reserved(STRINGS_FORMAT_ID)?.is_none());
+        // a corpus from a newer build is left alone
+        let mut newer = writer.manifest()?.expect("manifest");
+        newer.format_version = FORMAT_VERSION + 1;
+        writer.write_reserved(MANIFEST_ID, Some(newer.to_bytes()?))?;
+        match writer.reader() {
+            Err(CorpusError::IncompatibleFormatError(_)) => (),
+            r => panic!("read a corpus from a newer build: {:?}", r.map(|_| ())),
+        }
+        newer.format_version = FORMAT_VERSION;
+        writer.write_reserved(MANIFEST_ID, Some(newer.to_bytes()?))?;
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        match reader.hydrate_obj(&reader.read_obj(1u128.to_be_bytes())?)? {
+            HydratedEntity::Author(a) => assert_eq!(a.name(), "Mary"),
+            e => panic!("wrong entity {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_empty_strings() -> CorpusResult<()> {
+        let config = _test_config("migrate-empty-strings");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        let authors = [(1, "Mary", ""), (2, "", "no"), (3, "Al", "")];
+        writer.write_objs(
+            authors
+                .iter()
+                .map(|(id, name, notes)| {
+                    HydratedEntity::Author(HydratedAuthor::new(
+                        *id,
+                        name.to_string(),
+                        notes.to_string(),
+                    ))
+                })
+                .collect::<Vec<HydratedEntity>>(),
+        )?;
+        assert_eq!(
+            writer.read_reserved(strings_page_id(0))?,
+            Some(b"MarynoAl".to_vec())
+        );
+        // as format 0 stored them: empty strings start where the next
+        // string went, or at the end
+        let old = [
+            [StringRef::new(0, 3), StringRef::new(4, 0)],
+            [StringRef::new(4, 0), StringRef::new(4, 1)],
+            [StringRef::new(6, 1), StringRef::new(8, 0)],
+        ];
+        let mut page = writer.stored_page(0)?.expect("page");
+        for (entity, refs) in page.0.values_mut().zip(old) {
+            for (string_ref, old) in entity.string_refs_mut().into_iter().zip(refs) {
+                *string_ref = old;
+            }
+        }
+        writer.write_raw(vec![(0, Some(page.to_bytes()?)), (MANIFEST_ID, None)])?;
+        writer.migrate()?;
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        for (id, name, notes) in authors {
+            match reader.hydrate_obj(&reader.read_obj((id as u128).to_be_bytes())?)? {
+                HydratedEntity::Author(a) => assert_eq!((a.name(), a.notes()), (name, notes)),
+                e => panic!("wrong entity {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resume_interrupted_migration() -> CorpusResult<()> {
+        let config = _test_config("migrate-resume");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        let second = (1u128 << 64) | 1;
+        writer.write_objs(vec![
+            HydratedEntity::Author(HydratedAuthor::new(1, "Mary".into(), "n".into())),
+            HydratedEntity::Author(HydratedAuthor::new(second, "Al".into(), "n".into())),
+        ])?;
+        // as left by a migration stopped after page 0: page 1 still has
+        // inclusive lengths, and the format hasn't been recorded
+        let mut page = writer.stored_page(1)?.expect("page");
+        for entity in page.0.values_mut() {
+            for string_ref in entity.string_refs_mut() {
+                *string_ref = StringRef::new(string_ref.start, string_ref.length() - 1);
+            }
+        }
+        writer.write_raw(vec![
+            (1, Some(page.to_bytes()?)),
+            (MIGRATION_PROGRESS_ID, Some(0u64.to_be_bytes().to_vec())),
+            (MANIFEST_ID, None),
+        ])?;
+        assert_eq!(writer.with_db(stored_format_version)?, Some(0));
+        writer.migrate()?;
+        assert!(writer.read_reserved(MIGRATION_PROGRESS_ID)?.is_none());
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        for (id, name) in [(1, "Mary"), (second, "Al")] {
+            match reader.hydrate_obj(&reader.read_obj(id.to_be_bytes())?)? {
+                HydratedEntity::Author(a) => assert_eq!(a.name(), name),
+                e => panic!("wrong entity {e:?}"),
+            }
+        }
+        Ok(())
+    }
+}
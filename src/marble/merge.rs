@@ -0,0 +1,255 @@
+use crate::config::CorpusConfig;
+use crate::entities::{CorpusEntity, HydratedEntity, ObjType};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::manifest::{Manifest, MANIFEST_ID};
+use crate::marble::read::ReadState;
+use crate::marble::write::WriteState;
+use crate::marble::{CorpusHydrate, CorpusState, CorpusWrite};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeOptions {
+    /// import authors as the author already here with the same name, if
+    /// there is one
+    pub dedupe_authors: bool,
+}
+
+/// how many of each entity were imported
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeReport {
+    pub authors: usize,
+    pub collections: usize,
+    pub documents: usize,
+    pub tokens: usize,
+    /// authors that weren't imported, since one with their name already was
+    pub deduplicated_authors: usize,
+}
+
+impl CorpusState<WriteState> {
+    /// import every entity from the corpus `other` describes. everything
+    /// gets a freshly allocated id, and references are rewritten to match.
+    /// each type is imported in turn, so references always resolve, and
+    /// everything is committed as one transaction once it's all been read:
+    /// a merge that fails leaves this corpus as it was, and can simply be
+    /// run again. subcorpora, artifacts and the dictionary aren't merged
+    pub(crate) fn merge(
+        &self,
+        other: CorpusConfig,
+        options: MergeOptions,
+    ) -> CorpusResult<MergeReport> {
+        let other = CorpusState::<ReadState>::new(other)?;
+        if let Some(raw) = other.read_reserved(MANIFEST_ID)? {
+            let schemas = Manifest::from_bytes(&raw)?.label_schemas;
+            if schemas != self.label_schemas()? {
+                return Err(CorpusError::ConfigurationError(format!(
+                    "can't merge a corpus labelled with {schemas:?}"
+                )));
+            }
+        }
+        let mut report = MergeReport::default();
+        let mut authors = if options.dedupe_authors {
+            self.author_names()?
+        } else {
+            HashMap::new()
+        };
+        // (type, id over there) -> id here. nothing refers to tokens, so
+        // only authors, collections and documents are recorded
+        let mut ids: HashMap<(ObjType, u128), u128> = HashMap::new();
+        let page_ids = other.page_ids()?;
+        let mut transaction = self.transaction();
+        for pass in [
+            ObjType::Author,
+            ObjType::Collection,
+            ObjType::Document,
+            ObjType::Token,
+        ] {
+            for page_id in page_ids.iter() {
+                let page = other.page(*page_id)?;
+                let mut imports = Vec::new();
+                for entity in page.0.values().filter(|e| e.obj_type() == pass) {
+                    let hydrated = other.hydrate_obj(entity)?;
+                    if let HydratedEntity::Author(ref a) = hydrated {
+                        if let Some(id) = authors.get(a.name()) {
+                            ids.insert((pass, entity.id()), *id);
+                            report.deduplicated_authors += 1;
+                            continue;
+                        }
+                    }
+                    imports.push((entity, hydrated));
+                }
+                if imports.is_empty() {
+                    continue;
+                }
+                let new_ids = self.allocate_ids(pass, imports.len())?;
+                let mut objs = Vec::with_capacity(imports.len());
+                for ((entity, hydrated), id) in imports.into_iter().zip(new_ids) {
+                    let remap = |t: ObjType, old: u128| {
+                        ids.get(&(t, old))
+                            .copied()
+                            .ok_or(CorpusError::MissingReferenceError(entity.obj_id(), t, old))
+                    };
+                    let obj = match hydrated {
+                        HydratedEntity::Author(a) => {
+                            if options.dedupe_authors {
+                                authors.insert(a.name().to_string(), id);
+                            }
+                            report.authors += 1;
+                            HydratedEntity::Author(a.with_id(id))
+                        }
+                        HydratedEntity::Collection(c) => {
+                            report.collections += 1;
+                            HydratedEntity::Collection(c.with_id(id))
+                        }
+                        HydratedEntity::Document(d) => {
+                            report.documents += 1;
+                            HydratedEntity::Document(d.remapped(
+                                id,
+                                remap(ObjType::Author, d.author_id())?,
+                                remap(ObjType::Collection, d.collection_id())?,
+                            ))
+                        }
+                        HydratedEntity::Token(t) => {
+                            report.tokens += 1;
+                            HydratedEntity::Token(t.remapped(
+                                id,
+                                remap(ObjType::Document, t.document_id())?,
+                                remap(ObjType::Author, t.author_id())?,
+                            ))
+                        }
+                    };
+                    if pass != ObjType::Token {
+                        ids.insert((pass, entity.id()), id);
+                    }
+                    objs.push(obj);
+                }
+                transaction.write_objs(objs);
+            }
+        }
+        transaction.commit()?;
+        Ok(report)
+    }
+    /// the id of each author here, by name
+    fn author_names(&self) -> CorpusResult<HashMap<String, u128>> {
+        let reader = self.reader()?;
+        let mut names = HashMap::new();
+        for page_id in reader.page_ids()? {
+            let page = reader.page(page_id)?;
+            for entity in page.0.values() {
+                if let CorpusEntity::Author(_) = entity {
+                    if let HydratedEntity::Author(a) = reader.hydrate_obj(entity)? {
+                        names.entry(a.name().to_string()).or_insert(entity.id());
+                    }
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::document::HydratedDocument;
+    use crate::entities::obj_id;
+    use crate::marble::{_test_config, _test_document, _test_token};
+
+    #[test]
+    fn merge_remaps_ids() -> CorpusResult<()> {
+        let other_config = _test_config("merge-other");
+        let other = CorpusState::<WriteState>::new(other_config.clone())?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.push(HydratedEntity::Author(HydratedAuthor::new(
+            5,
+            "b".into(),
+            "n".into(),
+        )));
+        objs.push(HydratedEntity::Document(HydratedDocument::new(
+            6,
+            5,
+            3,
+            Default::default(),
+            "e".into(),
+        )));
+        objs.extend([_test_token(1, 1, 2), _test_token(2, 6, 5)]);
+        other.write_objs(objs)?;
+        drop(other);
+
+        let writer = CorpusState::<WriteState>::new(_test_config("merge"))?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.push(_test_token(1, 1, 2));
+        writer.write_objs(objs)?;
+        let report = writer.merge(
+            other_config,
+            MergeOptions {
+                dedupe_authors: true,
+            },
+        )?;
+        assert_eq!(
+            report,
+            MergeReport {
+                authors: 1,
+                collections: 1,
+                documents: 2,
+                tokens: 2,
+                deduplicated_authors: 1,
+            }
+        );
+        assert!(writer.verify(false)?.problems.is_empty());
+        let names = writer.author_names()?;
+        assert_eq!(names.len(), 2);
+        // the imported token of document 6 now belongs to b's new document
+        let reader = writer.reader()?;
+        let tokens = writer
+            .page_ids()?
+            .into_iter()
+            .map(|p| reader.page(p))
+            .collect::<CorpusResult<Vec<_>>>()?;
+        let imported = tokens
+            .iter()
+            .flat_map(|p| p.0.values())
+            .filter_map(|e| match e {
+                CorpusEntity::Token(t) if t.position() == 2 => Some(*t),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].author_id(), names["b"]);
+        let (page_id, key) = obj_id(imported[0].document_id(), ObjType::Document);
+        let document = reader.page(page_id)?.0[&key];
+        match reader.hydrate_obj(&document)? {
+            HydratedEntity::Document(d) => assert_eq!(d.author_id(), names["b"]),
+            e => panic!("wrong entity {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn failed_merge_imports_nothing() -> CorpusResult<()> {
+        let other_config = _test_config("merge-failed-other");
+        let other = CorpusState::<WriteState>::new(other_config.clone())?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.extend(_test_document(4, 2, 3).pop());
+        objs.extend([_test_token(1, 1, 2), _test_token(2, 4, 2)]);
+        other.write_objs(objs)?;
+        // as left by a writer that didn't check references
+        let mut documents = other.stored_page(0)?.expect("page 0");
+        documents.0.remove(&obj_id(4, ObjType::Document).1);
+        other.write_raw(vec![(0, Some(documents.to_bytes()?))])?;
+        drop(other);
+
+        let writer = CorpusState::<WriteState>::new(_test_config("merge-failed"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        let generation = writer.reader()?.generation()?;
+        match writer.merge(other_config, MergeOptions::default()) {
+            Err(CorpusError::MissingReferenceError(_, ObjType::Document, 4)) => (),
+            r => panic!("merged a dangling token: {r:?}"),
+        }
+        // the authors, collections and documents read before the token
+        // that failed weren't written either
+        assert_eq!(writer.reader()?.generation()?, generation);
+        assert_eq!(writer.page_ids()?, vec![0]);
+        assert_eq!(writer.stored_page(0)?.expect("page 0").0.len(), 3);
+        Ok(())
+    }
+}
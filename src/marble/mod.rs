@@ -1,11 +1,26 @@
+pub(crate) mod backup;
+pub(crate) mod cache;
+pub(crate) mod integrity;
+pub(crate) mod maintenance;
+pub(crate) mod manifest;
+pub(crate) mod merge;
+pub(crate) mod paging;
 pub(crate) mod read;
+pub(crate) mod transaction;
+pub(crate) mod verify;
 pub(crate) mod write;
 
-use crate::entities::{CorpusEntity, HydratedEntity, Id};
+use crate::entities::dictionary::Dictionary;
+use crate::entities::strings::Strings;
+use crate::entities::token::TokenRecords;
+use crate::entities::token_columns::TokenColumns;
+use crate::entities::{
+    obj_id, CorpusEntity, HasObjId, HasReferences, HydratedEntity, Id, StringRef, Token,
+};
 use crate::errors::{CorpusError, CorpusResult};
 use minicbor::{Decode, Encode};
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, RwLock};
 
 #[derive(Debug)]
@@ -29,7 +44,12 @@ pub(crate) trait CorpusRead {
 }
 
 pub(crate) trait CorpusWrite {
+    /// insert entities, overwriting any with the same id
     fn write_objs(&self, objs: impl AsRef<[HydratedEntity]>) -> CorpusResult<()>;
+    /// replace entities, all of which must already exist
+    fn update_objs(&self, objs: impl AsRef<[HydratedEntity]>) -> CorpusResult<()>;
+    /// remove entities by `obj_id`, all of which must exist
+    fn delete_objs(&self, obj_ids: impl AsRef<[(u64, u64)]>) -> CorpusResult<()>;
 }
 
 pub(crate) trait CorpusHydrate: CorpusRead {
@@ -42,28 +62,385 @@ pub(crate) trait CorpusHydrate: CorpusRead {
 #[cbor(transparent)]
 pub struct Page(#[n(0)] pub BTreeMap<u64, CorpusEntity>);
 
+/// how pages are laid out when written. pages in either format can be read
+/// back whatever the current setting
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PageFormat {
+    /// a CBOR map of entities
+    #[default]
+    Cbor,
+    /// `TOKEN_RECORDS_PAGE` followed by fixed-size token records, for pages
+    /// holding only tokens. any other page is still written as CBOR
+    TokenRecords,
+    /// `TOKEN_COLUMNS_PAGE` followed by compressed `TokenColumns`, likewise
+    /// only for pages holding only tokens
+    TokenColumns,
+}
+
+/// first byte of a token records page. CBOR pages always start with a map
+/// header, which this can't be
+pub(crate) const TOKEN_RECORDS_PAGE: u8 = 1;
+/// first byte of a token columns page
+pub(crate) const TOKEN_COLUMNS_PAGE: u8 = 2;
+/// what a btree costs per entry on top of the entry itself: node headers,
+/// child pointers and nodes that aren't full
+const BTREE_ENTRY_OVERHEAD: usize = 16;
+
 impl Page {
+    /// roughly how many bytes the decoded page takes up in memory.
+    /// entities keep their strings elsewhere, so they own no heap of
+    /// their own
+    pub(crate) fn memory_size(&self) -> usize {
+        std::mem::size_of::<Page>()
+            + self.0.len() * (std::mem::size_of::<(u64, CorpusEntity)>() + BTREE_ENTRY_OVERHEAD)
+    }
     pub fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
         let mut v = Vec::with_capacity(self.0.len());
         minicbor::encode::<&Page, &mut Vec<u8>>(self, v.as_mut())
             .map_err(|_| CorpusError::EncodingError("Page encoding error".to_string()))?;
         Ok(v)
     }
+    /// encode in `format`, falling back to CBOR where it doesn't apply
+    pub(crate) fn encode(&self, format: PageFormat) -> CorpusResult<Vec<u8>> {
+        if format == PageFormat::Cbor || !self.only_tokens() {
+            return self.to_bytes();
+        }
+        let mut v = Vec::new();
+        if format == PageFormat::TokenRecords {
+            v.push(TOKEN_RECORDS_PAGE);
+            v.extend_from_slice(TokenRecords::from_tokens(self.tokens()).as_bytes());
+        } else {
+            v.push(TOKEN_COLUMNS_PAGE);
+            v.extend_from_slice(&TokenColumns::encode(self.tokens()));
+        }
+        Ok(v)
+    }
+    pub(crate) fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.0.values().filter_map(|entity| match entity {
+            CorpusEntity::Token(t) => Some(t),
+            _ => None,
+        })
+    }
+    /// whether every entity is a token stored under its own key, so the
+    /// keys can be recovered from the records
+    fn only_tokens(&self) -> bool {
+        self.0.iter().all(|(key, entity)| match entity {
+            CorpusEntity::Token(t) => t.obj_id().1 == *key,
+            _ => false,
+        })
+    }
+    /// decode a page written in any `PageFormat`
+    pub fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        let tokens = match raw.split_first() {
+            Some((&TOKEN_RECORDS_PAGE, records)) => TokenRecords::from_vec(records.to_vec())?
+                .iter()
+                .map(|record| record.to_token())
+                .collect(),
+            Some((&TOKEN_COLUMNS_PAGE, columns)) => TokenColumns::new(columns)?.to_tokens()?,
+            _ => {
+                return minicbor::decode::<Page>(raw)
+                    .map_err(|_| CorpusError::DecodingError("loading page".to_string()))
+            }
+        };
+        Ok(Page(
+            tokens
+                .into_iter()
+                .map(|token: Token| (token.obj_id().1, CorpusEntity::Token(token)))
+                .collect(),
+        ))
+    }
+    /// the tokens stored in `raw`, whatever the page format, packed as
+    /// records. CBOR pages are decoded an entity at a time, without
+    /// building the page's map
+    pub(crate) fn token_records(mut raw: Vec<u8>) -> CorpusResult<TokenRecords> {
+        match raw.first() {
+            Some(&TOKEN_RECORDS_PAGE) => {
+                raw.remove(0);
+                TokenRecords::from_vec(raw)
+            }
+            Some(&TOKEN_COLUMNS_PAGE) => Ok(TokenRecords::from_tokens(
+                TokenColumns::new(&raw[1..])?.to_tokens()?.iter(),
+            )),
+            _ => {
+                let error = |_| CorpusError::DecodingError("loading page".to_string());
+                let mut decoder = minicbor::Decoder::new(&raw);
+                let mut records = TokenRecords::default();
+                for entry in decoder.map_iter::<u64, CorpusEntity>().map_err(error)? {
+                    if let (_, CorpusEntity::Token(token)) = entry.map_err(error)? {
+                        records.push(&token);
+                    }
+                }
+                Ok(records)
+            }
+        }
+    }
+    /// rewrite `strings` keeping only the ranges this page still refers to,
+    /// and point every reference at its new position
+    pub(crate) fn compact_strings(&mut self, strings: &Strings) -> CorpusResult<Strings> {
+        let live = self
+            .0
+            .values()
+            .flat_map(|entity| entity.string_refs())
+            .collect::<Vec<StringRef>>();
+        let (compacted, updates) = strings.compact(live.iter())?;
+        for entity in self.0.values_mut() {
+            for string_ref in entity.string_refs_mut() {
+                *string_ref = updates.remap(string_ref)?;
+            }
+        }
+        Ok(compacted)
+    }
+}
+
+/// strings for page `n` live at `n | STRINGS_PAGE_FLAG`
+pub(crate) const STRINGS_PAGE_FLAG: u64 = 0x8000_0000_0000_0000;
+/// marble ids with this flag hold corpus metadata rather than entities
+pub(crate) const RESERVED_PAGE_FLAG: u64 = 0x4000_0000_0000_0000;
+pub(crate) const PAGE_DIRECTORY_ID: u64 = RESERVED_PAGE_FLAG;
+pub(crate) const ARTIFACT_DIRECTORY_ID: u64 = RESERVED_PAGE_FLAG | 1;
+pub(crate) const SUBCORPORA_ID: u64 = RESERVED_PAGE_FLAG | 2;
+pub(crate) const DICTIONARY_ID: u64 = RESERVED_PAGE_FLAG | 3;
+/// marks format version 1, from before the manifest. corpora with neither
+/// were written with inclusive `StringRef` lengths
+pub(crate) const STRINGS_FORMAT_ID: u64 = RESERVED_PAGE_FLAG | 4;
+pub(crate) const STRINGS_FORMAT: u8 = 1;
+/// bumped by every committed write, so readers can tell their view is stale
+pub(crate) const GENERATION_ID: u64 = RESERVED_PAGE_FLAG | 5;
+/// derived artifacts are allocated upwards from here
+pub(crate) const FIRST_ARTIFACT_ID: u64 = RESERVED_PAGE_FLAG | 0x1_0000_0000;
+
+pub(crate) fn strings_page_id(page_id: u64) -> u64 {
+    page_id | STRINGS_PAGE_FLAG
+}
+
+/// token counts for page `n` live at `n | COUNTS_PAGE_FLAG`
+pub(crate) const COUNTS_PAGE_FLAG: u64 = STRINGS_PAGE_FLAG | RESERVED_PAGE_FLAG;
+
+pub(crate) fn counts_page_id(page_id: u64) -> u64 {
+    page_id | COUNTS_PAGE_FLAG
+}
+
+/// the corpus generation; 0 before anything has been written
+pub(crate) fn read_generation(db: &marble::Marble) -> CorpusResult<u64> {
+    match db.read(GENERATION_ID)? {
+        Some(raw) => Ok(u64::from_be_bytes(raw.as_ref().try_into().map_err(
+            |_| CorpusError::DecodingError("corpus generation".to_string()),
+        )?)),
+        None => Ok(0),
+    }
+}
+
+/// the corpus dictionary stored as `raw`, if there is one
+pub(crate) fn decode_dictionary<B: AsRef<[u8]>>(
+    raw: Option<B>,
+) -> CorpusResult<Option<Arc<Dictionary>>> {
+    raw.map(|raw| Dictionary::from_bytes(raw.as_ref()).map(Arc::new))
+        .transpose()
+}
+
+/// ids of every entity page in the corpus
+#[repr(transparent)]
+#[derive(Debug, Decode, Default, Encode, Clone)]
+#[cbor(transparent)]
+pub struct PageDirectory(#[n(0)] pub BTreeSet<u64>);
+
+impl PageDirectory {
+    pub fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let mut v = Vec::with_capacity(self.0.len() * 9);
+        minicbor::encode::<&PageDirectory, &mut Vec<u8>>(self, v.as_mut())
+            .map_err(|_| CorpusError::EncodingError("Page directory encoding error".to_string()))?;
+        Ok(v)
+    }
+    pub fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        minicbor::decode::<PageDirectory>(raw)
+            .map_err(|_| CorpusError::DecodingError("loading page directory".to_string()))
+    }
+}
+
+/// names of derived artifacts (e.g. n-gram counts), the marble ids they
+/// are stored under and the generation each was computed at. artifacts are
+/// dropped whenever entities are written, and `since` records when that
+/// last happened, so results computed before it can't be saved
+#[derive(Debug, Decode, Default, Encode, Clone)]
+pub struct ArtifactDirectory {
+    #[n(0)]
+    pub ids: BTreeMap<String, u64>,
+    /// the generation entities were last written at
+    #[n(1)]
+    pub since: u64,
+    #[n(2)]
+    pub generations: BTreeMap<String, u64>,
+    /// bumped by every artifact saved, which leaves the generation alone,
+    /// so a copy of the store can tell one was saved while it was taken
+    #[n(3)]
+    pub saves: Option<u64>,
 }
 
-#[macro_export]
-macro_rules! env_default {
-    ($label:literal, $default:literal, $t:ty) => {
-        match env::var(format!("MARBLE_{}", $label)).ok() {
-            None => $default,
-            Some(v) => v.parse::<$t>().map_err(|_| {
-                CorpusError::ConfigurationError(format!("Invalid MARBLE_{}", $label))
-            })?,
+impl ArtifactDirectory {
+    pub fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let mut v = Vec::new();
+        minicbor::encode::<&ArtifactDirectory, &mut Vec<u8>>(self, v.as_mut()).map_err(|_| {
+            CorpusError::EncodingError("Artifact directory encoding error".to_string())
+        })?;
+        Ok(v)
+    }
+    /// directories written before generations were kept are a bare map of
+    /// names to ids
+    pub fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        minicbor::decode::<ArtifactDirectory>(raw)
+            .or_else(|_| {
+                minicbor::decode::<BTreeMap<String, u64>>(raw).map(|ids| ArtifactDirectory {
+                    ids,
+                    ..Default::default()
+                })
+            })
+            .map_err(|_| CorpusError::DecodingError("loading artifact directory".to_string()))
+    }
+    /// id for `name`, allocating a new one if needed
+    pub fn id_for(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.ids.get(name) {
+            *id
+        } else {
+            let id = self
+                .ids
+                .values()
+                .max()
+                .map_or(FIRST_ARTIFACT_ID, |max| max + 1);
+            self.ids.insert(name.to_string(), id);
+            id
         }
-    };
+    }
+    /// computed before entities were last written. artifacts saved before
+    /// generations were kept were dropped by every write, so never are
+    pub fn is_stale(&self, name: &str) -> bool {
+        self.generations
+            .get(name)
+            .is_some_and(|generation| *generation < self.since)
+    }
+    /// drop `name` from the directory, returning the id it was stored under
+    pub fn remove(&mut self, name: &str) -> Option<u64> {
+        self.generations.remove(name);
+        self.ids.remove(name)
+    }
+}
+
+/// how often each token text and label occurs on one page. written along
+/// with every page, so frequencies over the whole corpus can be summed page
+/// by page without reading any tokens, and only pages that change have
+/// their counts redone
+#[derive(Debug, Decode, Default, Encode, Clone, PartialEq)]
+pub struct PageCounts {
+    #[n(0)]
+    pub texts: BTreeMap<String, u64>,
+    /// keyed by `Token.labels`
+    #[n(1)]
+    pub labels: BTreeMap<Id, u64>,
+    /// how many entities on the page refer to each author, collection and
+    /// document, by obj id, so deletes only read the pages that refer to
+    /// what they delete. missing from counts written before it was kept
+    #[n(2)]
+    pub references: Option<BTreeMap<(u64, u64), u64>>,
+}
+
+impl PageCounts {
+    pub(crate) fn from_page(page: &Page, strings: &Strings) -> CorpusResult<Self> {
+        let mut texts: HashMap<StringRef, u64> = HashMap::new();
+        let mut counts = Self::default();
+        let mut references = BTreeMap::new();
+        for entity in page.0.values() {
+            for (t, id) in entity.references() {
+                *references.entry(obj_id(id, t)).or_insert(0) += 1;
+            }
+        }
+        counts.references = Some(references);
+        for token in page.tokens() {
+            *texts.entry(token.text()).or_insert(0) += 1;
+            *counts
+                .labels
+                .entry(token.labels().to_be_bytes())
+                .or_insert(0) += 1;
+        }
+        for (string_ref, count) in texts {
+            *counts
+                .texts
+                .entry(strings.get_string(&string_ref)?)
+                .or_insert(0) += count;
+        }
+        Ok(counts)
+    }
+    pub fn to_bytes(&self) -> CorpusResult<Vec<u8>> {
+        let mut v = Vec::new();
+        minicbor::encode::<&PageCounts, &mut Vec<u8>>(self, v.as_mut())
+            .map_err(|_| CorpusError::EncodingError("Page counts encoding error".to_string()))?;
+        Ok(v)
+    }
+    pub fn from_bytes(raw: &[u8]) -> CorpusResult<Self> {
+        minicbor::decode::<PageCounts>(raw)
+            .map_err(|_| CorpusError::DecodingError("loading page counts".to_string()))
+    }
 }
 
 #[allow(unused_variables)]
 pub(crate) fn pf(object_id: u64, object_size: usize) -> u8 {
     *object_id.to_be_bytes().get(0).unwrap() << 1
 }
+
+#[cfg(test)]
+pub(crate) fn _test_config(name: &str) -> crate::config::CorpusConfig {
+    let path = std::env::temp_dir().join(format!("corpus-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    crate::config::CorpusConfig::new(path)
+}
+
+/// an author, a collection and a document by that author in that collection,
+/// for tests that need something for tokens to refer to
+#[cfg(test)]
+pub(crate) fn _test_document(
+    document: u128,
+    author: u128,
+    collection: u128,
+) -> Vec<HydratedEntity> {
+    use crate::entities::{
+        author::HydratedAuthor, collection::HydratedCollection, document::HydratedDocument,
+    };
+    let date = chrono::DateTime::<chrono::Utc>::default();
+    vec![
+        HydratedEntity::Author(HydratedAuthor::new(author, "a".into(), "n".into())),
+        HydratedEntity::Collection(HydratedCollection::new(
+            collection,
+            date,
+            "c".into(),
+            "n".into(),
+        )),
+        HydratedEntity::Document(HydratedDocument::new(
+            document,
+            author,
+            collection,
+            date,
+            "d".into(),
+        )),
+    ]
+}
+
+#[cfg(test)]
+pub(crate) fn _test_author(id: u128, name: &str) -> HydratedEntity {
+    HydratedEntity::Author(crate::entities::author::HydratedAuthor::new(
+        id,
+        name.to_string(),
+        "n".to_string(),
+    ))
+}
+
+/// token `id` on page 1, at position `id` of `document`
+#[cfg(test)]
+pub(crate) fn _test_token(id: u128, document: u128, author: u128) -> HydratedEntity {
+    HydratedEntity::Token(crate::entities::token::HydratedToken::new(
+        (1 << 64) | id,
+        document,
+        author,
+        0,
+        id as u64,
+        "x".into(),
+        0,
+    ))
+}
@@ -0,0 +1,237 @@
+use crate::entities::{parse_obj_id, ObjType};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::write::WriteState;
+use crate::marble::{CorpusState, RESERVED_PAGE_FLAG};
+use minicbor::{Decode, Encode};
+use std::collections::BTreeMap;
+
+/// how big a page may get. an entity's page is the high 64 bits of its id,
+/// so pages can't be split after the fact; instead `allocate_ids` hands out
+/// ids that respect `max_entities`, and writes that would grow a page past
+/// either limit fail with `PageFullError`. pages already past a limit can
+/// still be rewritten, as long as they don't grow. by default there are no
+/// limits
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PagingPolicy {
+    /// most entities on one page; 0 for no limit
+    pub max_entities: usize,
+    /// most bytes one encoded page, or its strings, may take; 0 for no limit
+    pub max_bytes: usize,
+}
+
+/// how much a page holds, as far as `PagingPolicy` is concerned
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct PageSize {
+    pub entities: usize,
+    pub page_bytes: usize,
+    pub strings_bytes: usize,
+}
+
+impl PagingPolicy {
+    /// error if page `page_id`, going from `before` to `after`, grows past
+    /// the policy. each limit only applies if what it limits grew
+    pub(crate) fn check(
+        &self,
+        page_id: u64,
+        before: PageSize,
+        after: PageSize,
+    ) -> CorpusResult<()> {
+        let over = |limit: usize, before: usize, after: usize| {
+            limit != 0 && after > before && after > limit
+        };
+        if over(self.max_entities, before.entities, after.entities)
+            || over(self.max_bytes, before.page_bytes, after.page_bytes)
+            || over(self.max_bytes, before.strings_bytes, after.strings_bytes)
+        {
+            return Err(CorpusError::PageFullError(
+                page_id,
+                after.entities,
+                after.page_bytes.max(after.strings_bytes),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// the page each entity type is currently being allocated into. pages are
+/// only ever opened past the highest page id in use, so allocated ids never
+/// collide with stored ones. kept in the manifest between sessions
+#[derive(Clone, Debug, Decode, Default, Encode, PartialEq)]
+pub(crate) struct Allocator {
+    #[n(0)]
+    next_page: Option<u64>,
+    /// (page id, ids used) keyed by `ObjType as u64`
+    #[n(1)]
+    open: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Allocator {
+    /// whether pages this has yet to open are already in use
+    pub(crate) fn is_behind(&self, page_ids: &[u64]) -> bool {
+        match (self.next_page, page_ids.last()) {
+            (Some(next), Some(last)) => next <= *last,
+            _ => false,
+        }
+    }
+    /// account for entities written to `page_id` under `keys`, which may
+    /// not have come from `allocate_ids`, so it never hands them out again
+    pub(crate) fn written(&mut self, page_id: u64, keys: impl IntoIterator<Item = u64>) {
+        if let Some(next) = self.next_page.as_mut() {
+            *next = (*next).max(page_id + 1);
+        }
+        for key in keys {
+            let Ok(t) = parse_obj_id(key) else {
+                continue;
+            };
+            if let Some((open, used)) = self.open.get_mut(&(t as u64)) {
+                let low = key & 0x0FFF_FFFF_FFFF_FFFF;
+                if *open == page_id && low >= *used {
+                    *used = low + 1;
+                }
+            }
+        }
+    }
+}
+
+impl CorpusState<WriteState> {
+    pub(crate) fn paging_policy(&self) -> CorpusResult<PagingPolicy> {
+        Ok(self
+            .lock()
+            .read()
+            .map_err(|_| CorpusError::LockError("Paging policy lock error".to_string()))?
+            .paging)
+    }
+    pub(crate) fn set_paging_policy(&self, policy: PagingPolicy) -> CorpusResult<()> {
+        self.lock()
+            .write()
+            .map_err(|_| CorpusError::LockError("Paging policy lock error".to_string()))?
+            .paging = policy;
+        Ok(())
+    }
+    /// `count` unused ids for entities of type `t`. each type fills pages of
+    /// its own, opening a new one whenever the current one is full. ids
+    /// allocated together stay on one page if they can: when they don't fit
+    /// in what's left of the current page but would fit in an empty one,
+    /// they start a new page. allocate a document's tokens in one call to
+    /// keep them together
+    pub(crate) fn allocate_ids(&self, t: ObjType, count: usize) -> CorpusResult<Vec<u128>> {
+        let page_ids = self.page_ids()?;
+        let mut st = self
+            .lock()
+            .write()
+            .map_err(|_| CorpusError::LockError("Allocate ids lock error".to_string()))?;
+        let max = match st.paging.max_entities {
+            0 => usize::MAX,
+            max => max,
+        };
+        let allocator = &mut st.allocator;
+        let next_page = allocator
+            .next_page
+            .get_or_insert_with(|| page_ids.last().map_or(0, |last| last + 1));
+        let mut open_page = || -> CorpusResult<u64> {
+            let page_id = *next_page;
+            if page_id >= RESERVED_PAGE_FLAG {
+                return Err(CorpusError::IdOverflowError("Page".into()));
+            }
+            *next_page += 1;
+            Ok(page_id)
+        };
+        let (mut page_id, mut used) = match allocator.open.get(&(t as u64)) {
+            Some((page_id, used)) => (*page_id, *used as usize),
+            None => (open_page()?, 0),
+        };
+        if used + count > max && count <= max {
+            (page_id, used) = (open_page()?, 0);
+        }
+        let mut ids = Vec::with_capacity(count);
+        for _ in 0..count {
+            if used == max {
+                (page_id, used) = (open_page()?, 0);
+            }
+            ids.push(((page_id as u128) << 64) | used as u128);
+            used += 1;
+        }
+        allocator.open.insert(t as u64, (page_id, used as u64));
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::token::HydratedToken;
+    use crate::entities::HydratedEntity;
+    use crate::marble::{_test_config, _test_document, CorpusWrite};
+
+    #[test]
+    fn allocated_pages_stay_within_policy() -> CorpusResult<()> {
+        let config = _test_config("paging");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        writer.set_paging_policy(PagingPolicy {
+            max_entities: 4,
+            max_bytes: 0,
+        })?;
+        let page = |id: u128| (id >> 64) as u64;
+        // a second document's tokens don't fit after the first's, so they
+        // start a page of their own
+        let first = writer.allocate_ids(ObjType::Token, 3)?;
+        let second = writer.allocate_ids(ObjType::Token, 2)?;
+        assert!(first.iter().all(|id| page(*id) == 1));
+        assert!(second.iter().all(|id| page(*id) == 2));
+        // too many for any one page, so they spill onto new ones
+        let long = writer.allocate_ids(ObjType::Token, 6)?;
+        assert_eq!(
+            long.iter().map(|id| page(*id)).collect::<Vec<u64>>(),
+            vec![2, 2, 3, 3, 3, 3]
+        );
+        assert_eq!(page(writer.allocate_ids(ObjType::Document, 1)?[0]), 4);
+        let tokens = first
+            .iter()
+            .chain(second.iter())
+            .chain(long.iter())
+            .map(|id| HydratedEntity::Token(HydratedToken::new(*id, 1, 2, 0, 0, "x".into(), 0)))
+            .collect::<Vec<HydratedEntity>>();
+        writer.write_objs(&tokens)?;
+        assert_eq!(writer.page_ids()?, vec![0, 1, 2, 3]);
+        let overflow =
+            HydratedEntity::Token(HydratedToken::new((3 << 64) | 9, 1, 2, 0, 0, "x".into(), 0));
+        match writer.write_objs(vec![overflow]) {
+            Err(CorpusError::PageFullError(3, 5, _)) => (),
+            r => panic!("page overfilled: {r:?}"),
+        }
+        writer.set_paging_policy(PagingPolicy {
+            max_entities: 0,
+            max_bytes: 16,
+        })?;
+        // rewriting a page that's already too big is fine, growing it isn't
+        writer.write_objs(&tokens[..1])?;
+        let extra =
+            HydratedEntity::Token(HydratedToken::new((1 << 64) | 3, 1, 2, 0, 0, "x".into(), 0));
+        match writer.write_objs(vec![extra]) {
+            Err(CorpusError::PageFullError(1, 4, _)) => (),
+            r => panic!("page overfilled: {r:?}"),
+        }
+        // allocation carries on where it left off, via the manifest
+        drop(writer);
+        let writer = CorpusState::<WriteState>::new(config)?;
+        assert_eq!(writer.allocate_ids(ObjType::Token, 1)?, vec![(3 << 64) | 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_ids_advance_allocation() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("paging-explicit"))?;
+        assert_eq!(writer.allocate_ids(ObjType::Token, 1)?, vec![0]);
+        // written without asking the allocator, past what it handed out and
+        // on a page it hasn't opened yet
+        writer.write_objs(_test_document(1, 2, 3))?;
+        writer.write_objs(vec![
+            HydratedEntity::Token(HydratedToken::new(5, 1, 2, 0, 0, "x".into(), 0)),
+            HydratedEntity::Token(HydratedToken::new((4 << 64) | 1, 1, 2, 0, 0, "x".into(), 0)),
+        ])?;
+        assert_eq!(writer.allocate_ids(ObjType::Token, 1)?, vec![6]);
+        assert_eq!(writer.allocate_ids(ObjType::Document, 1)?, vec![5 << 64]);
+        Ok(())
+    }
+}
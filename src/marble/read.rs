@@ -1,65 +1,109 @@
+use crate::config::CorpusConfig;
 use crate::entities;
+use crate::entities::dictionary::Dictionary;
 use crate::entities::strings::Strings;
-use crate::entities::{id_to_u128, CorpusEntity, HydratedEntity, Id};
-use crate::env_default;
+use crate::entities::token::TokenRecords;
+use crate::entities::token_columns::TokenColumns;
+use crate::entities::{CorpusEntity, HydratedEntity, Id, Token};
 use crate::errors::{CorpusError, CorpusResult};
-use crate::marble::{pf, CorpusHydrate, CorpusRead, CorpusState, Page};
+use crate::marble::cache::{CacheStats, LruCache};
+use crate::marble::manifest::check_format;
+use crate::marble::write::lock_file;
+use crate::marble::{
+    counts_page_id, decode_dictionary, read_generation, strings_page_id, ArtifactDirectory,
+    CorpusHydrate, CorpusRead, CorpusState, Page, PageCounts, PageDirectory, ARTIFACT_DIRECTORY_ID,
+    DICTIONARY_ID, PAGE_DIRECTORY_ID, TOKEN_COLUMNS_PAGE,
+};
 use std::borrow::{Borrow, BorrowMut};
-use std::collections::{BTreeSet, HashMap};
-use std::env;
-use std::path::PathBuf;
+use std::fs::File;
+use std::sync::Arc;
 
+/// a reader sees the corpus as of one generation. everything it caches
+/// belongs to that generation, and once a writer has moved past it anything
+/// not already cached can't be read until `refresh`
 #[derive(Debug)]
-struct ReadState {
+pub(crate) struct ReadState {
     db: marble::Marble,
-    cache: HashMap<u128, CorpusEntity>,
-    pages: BTreeSet<u64>,
-    strings_cache: HashMap<u64, Strings>,
+    generation: u64,
+    cache: LruCache<CacheKey, Cached>,
+    dictionary: Option<Arc<Dictionary>>,
+    /// shared lock on the corpus, held by readers that opened it themselves
+    _lock: Option<File>,
+}
+
+/// what's cached for a page id
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum CacheKey {
+    Page(u64),
+    Strings(u64),
+    Tokens(u64),
+    Columns(u64),
+}
+
+#[derive(Clone, Debug)]
+enum Cached {
+    Page(Arc<Page>),
+    Strings(Arc<Strings>),
+    Tokens(Arc<TokenRecords>),
+    Columns(Arc<Vec<u8>>),
+}
+
+impl ReadState {
+    /// read `id`, failing if the corpus is no longer at the pinned
+    /// generation before or after
+    fn read_pinned(&self, id: u64) -> CorpusResult<Option<Vec<u8>>> {
+        self.check_generation()?;
+        let raw = self.db.read(id)?.map(|raw| raw.to_vec());
+        self.check_generation()?;
+        Ok(raw)
+    }
+    fn check_generation(&self) -> CorpusResult<()> {
+        let current = read_generation(&self.db)?;
+        if current == self.generation {
+            Ok(())
+        } else {
+            Err(CorpusError::StaleSnapshotError(self.generation, current))
+        }
+    }
 }
 
 impl CorpusState<ReadState> {
-    pub(crate) fn default_config() -> CorpusResult<marble::Config> {
-        let pth = env::var("MARBLE_PATH").ok().unwrap_or("corpus".to_string());
-        let path = PathBuf::from(pth);
-        let zstd_compression_level = match env::var("MARBLE_ZSTD_COMPRESSION_LEVEL").ok() {
-            None => None,
-            Some(v) => Some(v.parse::<i32>().map_err(|_| {
-                CorpusError::ConfigurationError("Invalid MARBLE_ZSTD_COMPRESSION_LEVEL".to_string())
-            })?),
-        };
-        let fsync_each_batch = true;
-        let target_file_size = env_default!("TARGET_FILE_SIZE", 512_000_000usize, usize);
-        let file_compaction_percent = env_default!("FILE_COMPACTION_SIZE", 20u8, u8);
-        let max_object_size = env_default!("MAX_OBJECT_SIZE", 1_024_000usize, usize);
-        let small_file_cleanup_threshold =
-            env_default!("SMALL_FILE_CLEANUP_THRESHOLD", 128usize, usize);
-        let min_compaction_files = env_default!("MIN_COMPACTION_FILES", 128usize, usize);
-        Ok(marble::Config {
-            path,
-            zstd_compression_level,
-            fsync_each_batch,
-            target_file_size,
-            file_compaction_percent,
-            max_object_size,
-            small_file_cleanup_threshold,
-            min_compaction_files,
-            partition_function: pf,
-        })
-    }
-    pub(crate) fn new(config: marble::Config) -> CorpusResult<Self> {
+    /// open the corpus at `config.path` for reading. marble takes an
+    /// exclusive lock on its directory, so this fails with
+    /// `WriterLockedError` while a writer, or a reader in another process,
+    /// has the corpus open. use `WriteState::reader` to read alongside a
+    /// writer, or `open_backup` to read a copy of it
+    pub(crate) fn new(config: CorpusConfig) -> CorpusResult<Self> {
+        config.validate()?;
+        let lock = lock_file(&config.path)?;
+        lock.try_lock_shared()
+            .map_err(|_| CorpusError::WriterLockedError(config.path.clone()))?;
         let db = config
+            .marble_config()
             .open()
             .map_err(|e| CorpusError::BackingStorageError(e))?;
+        let reader = Self::from_db(db, config.cache_bytes)?;
+        reader._write_lock("Reader lock error".to_string())?._lock = Some(lock);
+        Ok(reader)
+    }
+    /// read through an already open handle, e.g. one shared with the writer
+    pub(crate) fn from_db(db: marble::Marble, cache_bytes: usize) -> CorpusResult<Self> {
+        check_format(&db)?;
+        let generation = read_generation(&db)?;
+        let dictionary = decode_dictionary(db.read(DICTIONARY_ID)?)?;
         let cs = ReadState {
             db,
-            cache: HashMap::new(),
-            pages: BTreeSet::new(),
-            strings_cache: HashMap::new(),
+            generation,
+            cache: LruCache::new(cache_bytes),
+            dictionary,
+            _lock: None,
         };
         CorpusState::_new(cs)
     }
+    /// the corpus at `MARBLE_PATH`, configured by its `corpus.toml` and the
+    /// environment
     pub(crate) fn default() -> CorpusResult<Self> {
-        Self::new(Self::default_config()?)
+        Self::new(CorpusConfig::load(CorpusConfig::default_path())?)
     }
     fn _read_lock(&self, msg: String) -> CorpusResult<std::sync::RwLockReadGuard<'_, ReadState>> {
         self.lock().read().map_err(|_| CorpusError::LockError(msg))
@@ -67,122 +111,277 @@ impl CorpusState<ReadState> {
     fn _write_lock(&self, msg: String) -> CorpusResult<std::sync::RwLockWriteGuard<'_, ReadState>> {
         self.lock().write().map_err(|_| CorpusError::LockError(msg))
     }
-    fn load_page(&self, page_id: u64) -> CorpusResult<Page> {
+    pub(crate) fn generation(&self) -> CorpusResult<u64> {
+        Ok(self
+            ._read_lock("Generation lock error".to_string())?
+            .generation)
+    }
+    /// move on to the latest generation, dropping everything cached from
+    /// the old one
+    pub(crate) fn refresh(&self) -> CorpusResult<u64> {
+        let mut st = self._write_lock("Refresh lock error".to_string())?;
+        let st = st.borrow_mut();
+        check_format(&st.db)?;
+        st.generation = read_generation(&st.db)?;
+        st.dictionary = decode_dictionary(st.db.read(DICTIONARY_ID)?)?;
+        st.cache.clear();
+        Ok(st.generation)
+    }
+    /// change how many bytes of pages and strings are kept cached
+    pub(crate) fn set_cache_budget(&self, bytes: usize) -> CorpusResult<()> {
+        self._write_lock("Cache budget lock error".to_string())?
+            .cache
+            .set_budget(bytes);
+        Ok(())
+    }
+    pub(crate) fn cache_stats(&self) -> CorpusResult<CacheStats> {
+        Ok(self
+            ._read_lock("Cache stats lock error".to_string())?
+            .cache
+            .stats())
+    }
+    /// ids of every entity page, in ascending order
+    pub(crate) fn page_ids(&self) -> CorpusResult<Vec<u64>> {
         if let Some(raw) = self
-            ._read_lock("loading page".to_string())?
+            ._read_lock("loading page directory".to_string())?
+            .read_pinned(PAGE_DIRECTORY_ID)?
+        {
+            Ok(PageDirectory::from_bytes(&raw)?.0.into_iter().collect())
+        } else {
+            Ok(Vec::new())
+        }
+    }
+    /// raw bytes of the reserved object `id`
+    pub(crate) fn read_reserved(&self, id: u64) -> CorpusResult<Option<Vec<u8>>> {
+        Ok(self
+            ._read_lock("loading reserved object".to_string())?
+            .borrow()
+            .db
+            .read(id)
+            .map_err(|e| CorpusError::BackingStorageError(e))?
+            .map(|raw| raw.to_vec()))
+    }
+    /// raw bytes of the derived artifact `name`, if it has been saved, and
+    /// the generation it was computed at
+    pub(crate) fn read_artifact(&self, name: &str) -> CorpusResult<Option<(Vec<u8>, u64)>> {
+        let st = self._read_lock("loading artifact".to_string())?;
+        let directory = if let Some(raw) = st
             .borrow()
             .db
-            .read(page_id)
+            .read(ARTIFACT_DIRECTORY_ID)
             .map_err(|e| CorpusError::BackingStorageError(e))?
         {
-            let decoded = minicbor::decode::<Page>(&raw)
-                .map_err(|_| CorpusError::DecodingError("loading page".to_string()))?;
-            Ok(decoded)
+            ArtifactDirectory::from_bytes(&raw)?
+        } else {
+            return Ok(None);
+        };
+        if let Some(id) = directory.ids.get(name) {
+            // artifacts saved before generations were kept were dropped by
+            // every write, so are of the current one
+            let generation = directory
+                .generations
+                .get(name)
+                .copied()
+                .unwrap_or(st.generation);
+            Ok(st
+                .borrow()
+                .db
+                .read(*id)
+                .map_err(|e| CorpusError::BackingStorageError(e))?
+                .map(|raw| (raw.to_vec(), generation)))
         } else {
-            Err(CorpusError::PageNotFoundError(page_id))
+            Ok(None)
         }
     }
-    fn cache_page(&self, page_id: u64, page: &Page) -> CorpusResult<()> {
-        let mut t = self._write_lock("Caching page".to_string())?;
-        let this = t.borrow_mut();
-        for obj in page.0.values().into_iter() {
-            this.cache.insert(obj.id(), *obj);
+    /// the stored token counts of page `page_id`, if it has any. pages last
+    /// written before counts were kept have none
+    pub(crate) fn page_counts(&self, page_id: u64) -> CorpusResult<Option<PageCounts>> {
+        self._read_lock("loading page counts".to_string())?
+            .read_pinned(counts_page_id(page_id))?
+            .map(|raw| PageCounts::from_bytes(&raw))
+            .transpose()
+    }
+    /// run `f` against every entity page in the corpus
+    pub(crate) fn scan<F>(&self, mut f: F) -> CorpusResult<()>
+    where
+        F: FnMut(u64, &Page) -> CorpusResult<()>,
+    {
+        for page_id in self.page_ids()? {
+            let page = self.load_page(page_id)?;
+            f(page_id, &page)?;
         }
-        this.pages.insert(page_id);
         Ok(())
     }
-    fn page_cached(&self, page_id: u64) -> CorpusResult<bool> {
-        Ok(self
-            ._read_lock("Checking page cache".to_string())?
-            .borrow()
-            .pages
-            .contains(&page_id))
+    /// read and decode page `page_id`, bypassing the cache
+    pub(crate) fn load_page(&self, page_id: u64) -> CorpusResult<Page> {
+        Page::from_bytes(&self.load_raw_page(page_id)?)
     }
-    fn entity_from_cache(&self, id: Id) -> CorpusResult<Option<CorpusEntity>> {
+    fn load_raw_page(&self, page_id: u64) -> CorpusResult<Vec<u8>> {
+        self._read_lock("loading page".to_string())?
+            .read_pinned(page_id)?
+            .ok_or(CorpusError::PageNotFoundError(page_id))
+    }
+    fn cached(&self, key: CacheKey) -> CorpusResult<Option<Cached>> {
         Ok(self
-            ._read_lock("Accessing read cache".to_string())?
-            .borrow()
+            ._write_lock("Accessing read cache".to_string())?
             .cache
-            .get(&id_to_u128(id))
+            .get(&key)
             .cloned())
     }
+    fn cache(&self, key: CacheKey, value: Cached, size: usize) -> CorpusResult<()> {
+        self._write_lock("Caching page".to_string())?
+            .cache
+            .insert(key, value, size);
+        Ok(())
+    }
+    /// page `page_id`, from the cache if possible
+    pub(crate) fn page(&self, page_id: u64) -> CorpusResult<Arc<Page>> {
+        if let Some(Cached::Page(page)) = self.cached(CacheKey::Page(page_id))? {
+            return Ok(page);
+        }
+        let page = Arc::new(self.load_page(page_id)?);
+        let size = page.memory_size();
+        self.cache(CacheKey::Page(page_id), Cached::Page(page.clone()), size)?;
+        Ok(page)
+    }
+    /// the tokens of page `page_id` packed as records, from the cache if
+    /// possible. the page itself is only cached if it already was. pages
+    /// without tokens aren't cached
+    fn token_records(&self, page_id: u64) -> CorpusResult<Arc<TokenRecords>> {
+        let key = CacheKey::Tokens(page_id);
+        if let Some(Cached::Tokens(records)) = self.cached(key)? {
+            return Ok(records);
+        }
+        let records = Arc::new(match self.cached(CacheKey::Page(page_id))? {
+            Some(Cached::Page(page)) => TokenRecords::from_tokens(page.tokens()),
+            _ => Page::token_records(self.load_raw_page(page_id)?)?,
+        });
+        if !records.is_empty() {
+            self.cache(
+                key,
+                Cached::Tokens(records.clone()),
+                records.as_bytes().len(),
+            )?;
+        }
+        Ok(records)
+    }
+    /// the tokens of page `page_id` encoded as `TokenColumns`, from the cache
+    /// if possible, or `None` if it holds no tokens. token columns pages are
+    /// used as stored, and pages without tokens aren't cached
+    fn token_columns(&self, page_id: u64) -> CorpusResult<Option<Arc<Vec<u8>>>> {
+        let key = CacheKey::Columns(page_id);
+        if let Some(Cached::Columns(columns)) = self.cached(key)? {
+            return Ok(Some(columns));
+        }
+        let tokens = match self.cached(CacheKey::Page(page_id))? {
+            Some(Cached::Page(page)) => page.tokens().copied().collect::<Vec<Token>>(),
+            _ => {
+                let mut raw = self.load_raw_page(page_id)?;
+                if raw.first() == Some(&TOKEN_COLUMNS_PAGE) {
+                    raw.remove(0);
+                    TokenColumns::new(&raw)?;
+                    let columns = Arc::new(raw);
+                    self.cache(key, Cached::Columns(columns.clone()), columns.len())?;
+                    return Ok(Some(columns));
+                }
+                Page::token_records(raw)?
+                    .iter()
+                    .map(|record| record.to_token())
+                    .collect()
+            }
+        };
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        let columns = Arc::new(TokenColumns::encode(tokens.iter()));
+        self.cache(key, Cached::Columns(columns.clone()), columns.len())?;
+        Ok(Some(columns))
+    }
+    /// run `f` against the tokens of `page_id` column by column, with their
+    /// strings. only the columns `f` asks for are decoded. `None`, with the
+    /// strings left unread, if the page holds no tokens
+    pub(crate) fn with_token_columns<F, T>(&self, page_id: u64, f: F) -> CorpusResult<Option<T>>
+    where
+        F: FnOnce(TokenColumns<'_>, &Strings) -> CorpusResult<T>,
+    {
+        let Some(columns) = self.token_columns(page_id)? else {
+            return Ok(None);
+        };
+        let columns = TokenColumns::new(&columns)?;
+        self.with_strings(page_id, |strings| f(columns, strings))
+            .map(Some)
+    }
+    /// run `f` against the tokens of `page_id` and their strings, both read
+    /// in place from the cache. nothing is allocated per token. `None`, with
+    /// the strings left unread, if the page holds no tokens
+    pub(crate) fn with_tokens<F, T>(&self, page_id: u64, f: F) -> CorpusResult<Option<T>>
+    where
+        F: FnOnce(&TokenRecords, &Strings) -> CorpusResult<T>,
+    {
+        let records = self.token_records(page_id)?;
+        if records.is_empty() {
+            return Ok(None);
+        }
+        self.with_strings(page_id, |strings| f(&records, strings))
+            .map(Some)
+    }
     fn load_strings(&self, strings_page_id: u64) -> CorpusResult<Strings> {
-        if let Some(raw) = self
-            ._read_lock("loading strings".to_string())?
-            .borrow()
-            .db
-            .read(strings_page_id)
-            .map_err(|e| CorpusError::BackingStorageError(e))?
-        {
-            Ok(Strings::from_bytes(&raw))
+        let st = self._read_lock("loading strings".to_string())?;
+        let st = st.borrow();
+        if let Some(raw) = st.read_pinned(strings_page_id)? {
+            Ok(Strings::from_bytes(&raw).with_dictionary(st.dictionary.clone()))
         } else {
             Err(CorpusError::PageNotFoundError(strings_page_id))
         }
     }
-    fn cache_strings(&self, strings_page_id: u64, strings: Strings) -> CorpusResult<()> {
-        self._write_lock("Caching strings".to_string())?
-            .borrow_mut()
-            .strings_cache
-            .insert(strings_page_id, strings);
-        Ok(())
-    }
-    fn strings_cached(&self, strings_page_id: u64) -> CorpusResult<bool> {
-        Ok(self
-            ._read_lock("Checking strings cache".to_string())?
-            .borrow()
-            .strings_cache
-            .contains_key(&strings_page_id))
+    /// run `f` against the (cached) strings belonging to `page_id`
+    pub(crate) fn with_strings<F, T>(&self, page_id: u64, f: F) -> CorpusResult<T>
+    where
+        F: FnOnce(&Strings) -> CorpusResult<T>,
+    {
+        let key = CacheKey::Strings(page_id);
+        let strings = match self.cached(key)? {
+            Some(Cached::Strings(strings)) => strings,
+            _ => {
+                let strings = Arc::new(self.load_strings(strings_page_id(page_id))?);
+                let size = strings.as_bytes().len();
+                self.cache(key, Cached::Strings(strings.clone()), size)?;
+                strings
+            }
+        };
+        f(&strings)
     }
 }
 
 impl CorpusRead for CorpusState<ReadState> {
     fn read_obj(&self, obj_id: Id) -> CorpusResult<CorpusEntity> {
-        if let Some(entity) = self.entity_from_cache(obj_id)? {
-            Ok(entity)
-        } else {
-            let (h, _) = entities::split_id(obj_id)?;
-            let page = self.load_page(h)?;
-            self.cache_page(h, &page)?;
-            Ok(self.entity_from_cache(obj_id)?.unwrap())
-        }
+        let (h, l) = entities::split_id(obj_id)?;
+        self.page(h)?
+            .0
+            .get(&l)
+            .copied()
+            .ok_or(CorpusError::EntityNotFoundError((h, l)))
     }
     fn read_objs(&self, obj_ids: impl AsRef<[Id]>) -> CorpusResult<Vec<CorpusEntity>> {
         let obj_ids = obj_ids.as_ref();
-        let mut cached: Vec<Id> = Vec::new();
-        let mut to_find: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut out: Vec<CorpusEntity> = Vec::with_capacity(obj_ids.len());
+        let mut current: Option<(u64, Arc<Page>)> = None;
         for id in obj_ids {
             let (h, l) = entities::split_id(*id)?;
-            if self.page_cached(h)? {
-                cached.push(*id);
-            } else {
-                to_find
-                    .entry(h)
-                    .and_modify(|ids| ids.push(l))
-                    .or_insert(vec![l]);
-            }
-        }
-        let mut out: Vec<CorpusEntity> = Vec::with_capacity(obj_ids.len());
-        for id in cached {
-            if let Some(entity) = self.entity_from_cache(id)? {
-                out.push(entity);
-            } else {
-                let (h, l) = entities::split_id(id)?;
-                to_find
-                    .entry(h)
-                    .and_modify(|ids| ids.push(l))
-                    .or_insert(vec![l]);
-            }
-        }
-        for (page_id, oids) in to_find.into_iter() {
-            let p = self.load_page(page_id)?;
-            self.cache_page(page_id, &p)?;
-            for id in oids {
-                if let Some(entity) = p.0.get(&id) {
-                    out.push(*entity);
-                } else {
-                    return Err(CorpusError::EntityNotFoundError((page_id, id)));
+            let page = match current {
+                Some((page_id, ref page)) if page_id == h => page.clone(),
+                _ => {
+                    let page = self.page(h)?;
+                    current = Some((h, page.clone()));
+                    page
                 }
-            }
+            };
+            out.push(
+                *page
+                    .0
+                    .get(&l)
+                    .ok_or(CorpusError::EntityNotFoundError((h, l)))?,
+            );
         }
         Ok(out)
     }
@@ -190,11 +389,132 @@ impl CorpusRead for CorpusState<ReadState> {
 
 impl CorpusHydrate for CorpusState<ReadState> {
     fn hydrate_obj(&self, entity: &CorpusEntity) -> CorpusResult<HydratedEntity> {
-        let page_id = entity.page_id();
-        let strings_page = page_id & 0x8000_0000u64;
-        todo!()
+        self.with_strings(entity.page_id(), |strings| entity.hydrate(strings))
     }
     fn hydrate_objs(&self, entities: &[CorpusEntity]) -> CorpusResult<Vec<HydratedEntity>> {
-        todo!()
+        entities
+            .iter()
+            .map(|entity| self.hydrate_obj(entity))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::token::HydratedToken;
+    use crate::marble::write::WriteState;
+    use crate::marble::{_test_author, _test_config, _test_document, CorpusWrite};
+
+    fn name(reader: &CorpusState<ReadState>, id: u128) -> CorpusResult<String> {
+        match reader.hydrate_obj(&reader.read_obj(id.to_be_bytes())?)? {
+            HydratedEntity::Author(a) => Ok(a.name().to_string()),
+            e => panic!("wrong entity {e:?}"),
+        }
+    }
+
+    #[test]
+    fn snapshot_until_refresh() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("snapshot"))?;
+        writer.write_objs(vec![
+            _test_author(1, "Mary"),
+            _test_author((1 << 64) | 2, "John"),
+        ])?;
+        let reader = writer.reader()?;
+        let pinned = reader.generation()?;
+        assert_eq!(name(&reader, 1)?, "Mary");
+        writer.update_objs(vec![
+            _test_author(1, "Marianne"),
+            _test_author((1 << 64) | 2, "Johnny"),
+        ])?;
+        assert_eq!(name(&reader, 1)?, "Mary");
+        match name(&reader, (1 << 64) | 2) {
+            Err(CorpusError::StaleSnapshotError(g, current)) => {
+                assert_eq!(g, pinned);
+                assert!(current > pinned);
+            }
+            r => panic!("read across generations: {r:?}"),
+        }
+        assert!(reader.refresh()? > pinned);
+        assert_eq!(name(&reader, 1)?, "Marianne");
+        assert_eq!(name(&reader, (1 << 64) | 2)?, "Johnny");
+        Ok(())
+    }
+
+    #[test]
+    fn page_cache_budget() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("page-cache"))?;
+        writer.write_objs(vec![
+            _test_author(1, "Mary"),
+            _test_author((1 << 64) | 2, "John"),
+        ])?;
+        let reader = writer.reader()?;
+        assert_eq!(name(&reader, 1)?, "Mary");
+        assert_eq!(name(&reader, 1)?, "Mary");
+        let stats = reader.cache_stats()?;
+        // page and strings page, each missed once then hit once
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 2));
+        // the page is charged for what it takes decoded, not stored
+        let page = writer.stored_page(0)?.expect("page");
+        assert_eq!(stats.bytes, page.memory_size() + "Maryn".len());
+        assert_eq!(name(&reader, (1 << 64) | 2)?, "John");
+        assert_eq!(reader.cache_stats()?.entries, 4);
+        reader.set_cache_budget(0)?;
+        let stats = reader.cache_stats()?;
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (0, 0, 4));
+        assert_eq!(name(&reader, 1)?, "Mary");
+        assert_eq!(reader.cache_stats()?.entries, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_copy_tokens() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("zero-copy"))?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.extend(
+            ["the", "cat", "the"]
+                .into_iter()
+                .enumerate()
+                .map(|(i, text)| {
+                    HydratedEntity::Token(HydratedToken::new(
+                        (1 << 64) | i as u128,
+                        1,
+                        2,
+                        0,
+                        i as u64,
+                        text.to_string(),
+                        0,
+                    ))
+                }),
+        );
+        writer.write_objs(objs)?;
+        let reader = writer.reader()?;
+        let texts = reader
+            .with_tokens(1, |records, strings| {
+                let bytes = strings.as_bytes().as_ptr_range();
+                records
+                    .iter()
+                    .map(|record| {
+                        let text = strings.get_str(&record.text())?;
+                        assert!(bytes.contains(&text.as_ptr()));
+                        Ok((record.position(), text.len()))
+                    })
+                    .collect::<CorpusResult<Vec<(u64, usize)>>>()
+            })?
+            .expect("tokens");
+        assert_eq!(texts, vec![(0, 3), (1, 3), (2, 3)]);
+        reader.with_tokens(1, |records, _| {
+            assert_eq!(records.len(), 3);
+            Ok(())
+        })?;
+        // records and strings, each missed once then hit once. the first
+        // records miss also looks for the decoded page, which misses too
+        let stats = reader.cache_stats()?;
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 2));
+        // page 0 holds the document and its author, and nothing is cached
+        assert!(reader.with_tokens(0, |_, _| Ok(()))?.is_none());
+        assert!(reader.with_token_columns(0, |_, _| Ok(()))?.is_none());
+        assert_eq!(reader.cache_stats()?.entries, 2);
+        Ok(())
     }
 }
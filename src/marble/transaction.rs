@@ -0,0 +1,168 @@
+use crate::entities::HydratedEntity;
+use crate::errors::CorpusResult;
+use crate::marble::write::{Change, WriteState};
+use crate::marble::CorpusState;
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug)]
+enum Op {
+    Write(HydratedEntity),
+    Update(HydratedEntity),
+    Delete(Vec<(u64, u64)>),
+}
+
+/// what a transaction has buffered so far: the latest version of everything
+/// it writes, and everything it deletes
+#[derive(Debug, Default)]
+pub(crate) struct Pending<'a> {
+    pub(crate) present: BTreeMap<(u64, u64), &'a HydratedEntity>,
+    pub(crate) removed: HashSet<(u64, u64)>,
+}
+
+/// writes, updates and deletes buffered in memory and sent to marble as a
+/// single batch by `commit`. if anything fails before then, nothing is
+/// written; dropping a transaction without committing rolls it back
+#[derive(Debug)]
+pub(crate) struct Transaction<'a> {
+    state: &'a CorpusState<WriteState>,
+    ops: Vec<Op>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(state: &'a CorpusState<WriteState>) -> Self {
+        Self {
+            state,
+            ops: Vec::new(),
+        }
+    }
+    /// insert entities, overwriting any with the same id
+    pub(crate) fn write_objs(&mut self, objs: impl AsRef<[HydratedEntity]>) {
+        self.ops
+            .extend(objs.as_ref().iter().cloned().map(Op::Write));
+    }
+    /// replace entities, which must exist by the time this is committed
+    pub(crate) fn update_objs(&mut self, objs: impl AsRef<[HydratedEntity]>) {
+        self.ops
+            .extend(objs.as_ref().iter().cloned().map(Op::Update));
+    }
+    /// remove entities by `obj_id`, along with whatever the delete policy
+    /// says has to go with them. the cascade is worked out on commit, over
+    /// what's stored then and what this transaction has written before it
+    pub(crate) fn delete_objs(&mut self, obj_ids: impl AsRef<[(u64, u64)]>) {
+        self.ops.push(Op::Delete(obj_ids.as_ref().to_vec()));
+    }
+    /// cascades are followed and references checked under the write lock,
+    /// against what's stored at the time, so nothing committed meanwhile
+    /// can leave them dangling
+    pub(crate) fn commit(self) -> CorpusResult<()> {
+        self.state.apply_changes(|st| {
+            let mut pending = Pending::default();
+            let mut changes: BTreeMap<u64, Vec<(u64, Change)>> = BTreeMap::new();
+            for op in self.ops.iter() {
+                let (obj, change) = match op {
+                    Op::Write(obj) => (obj, Change::Write(obj)),
+                    Op::Update(obj) => (obj, Change::Update(obj)),
+                    Op::Delete(obj_ids) => {
+                        for id in st.cascade_deletes(obj_ids, &pending)? {
+                            pending.present.remove(&id);
+                            pending.removed.insert(id);
+                            changes
+                                .entry(id.0)
+                                .or_default()
+                                .push((id.1, Change::Delete));
+                        }
+                        continue;
+                    }
+                };
+                let (page_id, key) = obj.obj_id();
+                pending.removed.remove(&(page_id, key));
+                pending.present.insert((page_id, key), obj);
+                changes.entry(page_id).or_default().push((key, change));
+            }
+            st.check_references(&pending)?;
+            Ok(changes)
+        })
+    }
+    /// discard everything buffered
+    pub(crate) fn rollback(self) {}
+}
+
+impl CorpusState<WriteState> {
+    pub(crate) fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::{obj_id, ObjType};
+    use crate::errors::CorpusError;
+    use crate::marble::{_test_config, _test_document, _test_token, CorpusWrite};
+
+    #[test]
+    fn transaction_all_or_nothing() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("transaction"))?;
+        let mut tx = writer.transaction();
+        tx.write_objs(_test_document(1, 1, 1));
+        tx.write_objs(vec![_test_token(1, 1, 1), _test_token(2, 1, 1)]);
+        tx.update_objs(vec![HydratedEntity::Author(HydratedAuthor::new(
+            (2 << 64) | 5,
+            "Nobody".into(),
+            "n".into(),
+        ))]);
+        match tx.commit() {
+            Err(CorpusError::EntityNotFoundError(_)) => (),
+            r => panic!("committed a bad update: {r:?}"),
+        }
+        assert!(writer.page_ids()?.is_empty());
+
+        let mut tx = writer.transaction();
+        tx.write_objs(_test_document(1, 1, 1));
+        tx.write_objs(vec![_test_token(1, 1, 1), _test_token(2, 7, 1)]);
+        match tx.commit() {
+            Err(CorpusError::MissingReferenceError(_, ObjType::Document, 7)) => (),
+            r => panic!("committed a dangling token: {r:?}"),
+        }
+        assert!(writer.page_ids()?.is_empty());
+
+        let mut tx = writer.transaction();
+        tx.write_objs(_test_document(1, 1, 1));
+        tx.rollback();
+        assert!(writer.page_ids()?.is_empty());
+
+        let mut tx = writer.transaction();
+        tx.write_objs(_test_document(1, 1, 1));
+        tx.write_objs(_test_document(2, 1, 1));
+        tx.write_objs(vec![_test_token(1, 1, 1), _test_token(2, 2, 1)]);
+        tx.delete_objs(vec![obj_id(1, ObjType::Document)]);
+        assert!(writer.page_ids()?.is_empty());
+        tx.commit()?;
+        assert_eq!(writer.page_ids()?, vec![0, 1]);
+        let tokens = writer.stored_page(1)?.expect("token page");
+        assert_eq!(tokens.0.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn interleaved_transactions() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("transaction-interleaved"))?;
+        writer.write_objs(_test_document(1, 1, 1))?;
+        let mut deleting = writer.transaction();
+        deleting.delete_objs(vec![obj_id(1, ObjType::Document)]);
+        let mut referring = writer.transaction();
+        referring.write_objs(_test_document(2, 1, 1).split_off(2));
+        // a token for the document commits after its delete is buffered,
+        // and its author is deleted before the new document commits
+        writer.write_objs(vec![_test_token(1, 1, 1)])?;
+        deleting.commit()?;
+        assert!(writer.stored_page(1)?.is_none());
+        writer.delete_objs(vec![obj_id(1, ObjType::Author)])?;
+        match referring.commit() {
+            Err(CorpusError::MissingReferenceError(_, ObjType::Author, 1)) => (),
+            r => panic!("committed a document of a deleted author: {r:?}"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+use crate::analysis::subcorpus::decode_subcorpora;
+use crate::entities::strings::Strings;
+use crate::entities::{obj_id, HasReferences, ObjType, StringRef};
+use crate::errors::{CorpusError, CorpusResult};
+use crate::marble::manifest::{read_manifest, MANIFEST_ID};
+use crate::marble::paging::Allocator;
+use crate::marble::write::WriteState;
+use crate::marble::{
+    counts_page_id, decode_dictionary, strings_page_id, ArtifactDirectory, CorpusState, Page,
+    PageCounts, PageDirectory, ARTIFACT_DIRECTORY_ID, DICTIONARY_ID, PAGE_DIRECTORY_ID,
+    SUBCORPORA_ID,
+};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// something wrong with the stored corpus. entities are named by the
+/// (page id, key) they're stored under
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// listed in the page directory but not stored
+    MissingPage(u64),
+    /// stored and referred to, but not listed in the page directory
+    UnlistedPage(u64),
+    /// stored, but doesn't decode
+    UnreadablePage(u64),
+    /// stored somewhere other than where `obj_id` says it belongs
+    MisplacedEntity((u64, u64), (u64, u64)),
+    /// a reference outside its page's strings or the dictionary, or to
+    /// bytes that aren't UTF-8
+    BadString((u64, u64), StringRef),
+    /// refers to an entity that isn't stored
+    MissingReference((u64, u64), ObjType, u128),
+    /// the stored token counts don't match the page
+    StalePageCounts(u64),
+    /// the saved subcorpora don't decode
+    UnreadableSubcorpora,
+    /// a saved subcorpus selects an author or collection that isn't stored
+    MissingSubcorpusReference(String, ObjType, u128),
+    /// listed in the artifact directory but not stored
+    MissingArtifact(String),
+    /// computed before entities were last written
+    StaleArtifact(String),
+    /// the stored allocator would hand out ids on pages already in use
+    StaleAllocator,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    pub pages: usize,
+    pub entities: usize,
+    /// everything found, repaired or not
+    pub problems: Vec<Problem>,
+    /// the problems that were fixed
+    pub repaired: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.len() == self.repaired.len()
+    }
+}
+
+/// keys stored on each readable page, so references can be resolved
+/// without keeping whole pages around
+#[derive(Default)]
+struct StoredKeys(HashMap<u64, Option<BTreeSet<u64>>>);
+
+impl StoredKeys {
+    /// whether `target` is stored. the first time a page outside the
+    /// directory turns out to be stored it's added to `unlisted`
+    fn contains(
+        &mut self,
+        corpus: &CorpusState<WriteState>,
+        target: (u64, u64),
+        unlisted: &mut Vec<u64>,
+    ) -> CorpusResult<bool> {
+        let stored = match self.0.entry(target.0) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let stored = match corpus.stored_page(target.0) {
+                    Ok(page) => page.map(|p| p.0.into_keys().collect()),
+                    Err(CorpusError::DecodingError(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                if stored.is_some() {
+                    unlisted.push(target.0);
+                }
+                e.insert(stored)
+            }
+        };
+        Ok(stored
+            .as_ref()
+            .is_some_and(|stored| stored.contains(&target.1)))
+    }
+}
+
+/// what the scan found that can be fixed. all of it is checked again
+/// under the write lock before anything is written
+#[derive(Default)]
+struct Repairs {
+    missing_pages: Vec<u64>,
+    unlisted_pages: Vec<u64>,
+    /// page id -> [(stored key, expected key)], for entities that belong
+    /// elsewhere on their own page
+    moves: BTreeMap<u64, Vec<(u64, u64)>>,
+    stale_counts: Vec<u64>,
+    missing_artifacts: Vec<String>,
+    stale_artifacts: Vec<String>,
+    stale_allocator: bool,
+}
+
+impl CorpusState<WriteState> {
+    /// check every page and its counts, then the references between
+    /// entities and from saved subcorpora, then the directories and
+    /// manifest against the data. with `repair`, fix what can be fixed
+    /// without losing anything: directory entries are added or dropped,
+    /// entities moved to a free key on their own page, counts redone, stale
+    /// or missing artifacts dropped and the allocator reset. everything else
+    /// is only reported
+    pub(crate) fn verify(&self, repair: bool) -> CorpusResult<VerifyReport> {
+        self.check_format()?;
+        let mut report = VerifyReport::default();
+        let mut repairs = Repairs::default();
+        let directory = match self.read_reserved(PAGE_DIRECTORY_ID)? {
+            Some(raw) => PageDirectory::from_bytes(&raw)?,
+            None => PageDirectory::default(),
+        };
+        let dictionary = decode_dictionary(self.read_reserved(DICTIONARY_ID)?)?;
+        let mut keys = StoredKeys::default();
+        for page_id in directory.0.iter().copied() {
+            let page = match self.stored_page(page_id) {
+                Ok(Some(page)) => page,
+                Ok(None) => {
+                    report.problems.push(Problem::MissingPage(page_id));
+                    repairs.missing_pages.push(page_id);
+                    continue;
+                }
+                Err(CorpusError::DecodingError(_)) => {
+                    report.problems.push(Problem::UnreadablePage(page_id));
+                    keys.0.insert(page_id, None);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            report.pages += 1;
+            report.entities += page.0.len();
+            let strings = match self.read_reserved(strings_page_id(page_id))? {
+                Some(raw) => Strings::from_bytes(&raw),
+                None => Strings::new(),
+            }
+            .with_dictionary(dictionary.clone());
+            let mut moves = Vec::new();
+            for (key, entity) in page.0.iter() {
+                let stored = (page_id, *key);
+                for string_ref in entity.string_refs() {
+                    if strings.get_str(&string_ref).is_err() {
+                        report.problems.push(Problem::BadString(stored, string_ref));
+                    }
+                }
+                let expected = entity.obj_id();
+                if expected != stored {
+                    report
+                        .problems
+                        .push(Problem::MisplacedEntity(stored, expected));
+                    if expected.0 == page_id {
+                        moves.push((stored.1, expected.1));
+                    }
+                }
+            }
+            if !moves.is_empty() {
+                repairs.moves.insert(page_id, moves);
+            }
+            // pages last written before counts were kept have none, and
+            // counts can't be redone over bad strings
+            if let (Some(raw), Ok(counts)) = (
+                self.read_reserved(counts_page_id(page_id))?,
+                PageCounts::from_page(&page, &strings),
+            ) {
+                if PageCounts::from_bytes(&raw).ok() != Some(counts) {
+                    report.problems.push(Problem::StalePageCounts(page_id));
+                    repairs.stale_counts.push(page_id);
+                }
+            }
+            keys.0.insert(page_id, Some(page.0.into_keys().collect()));
+        }
+        // entities are checked where they're stored, whether or not they
+        // will be moved, so pages only need decoding once more
+        for page_id in directory.0.iter().copied() {
+            let Ok(Some(page)) = self.stored_page(page_id) else {
+                continue;
+            };
+            for (key, entity) in page.0.iter() {
+                for (t, id) in entity.references() {
+                    let found = keys.contains(self, obj_id(id, t), &mut repairs.unlisted_pages)?;
+                    if !found {
+                        report
+                            .problems
+                            .push(Problem::MissingReference((page_id, *key), t, id));
+                    }
+                }
+            }
+        }
+        match decode_subcorpora(self.read_reserved(SUBCORPORA_ID)?) {
+            Ok(subcorpora) => {
+                for subcorpus in subcorpora {
+                    let selected = subcorpus
+                        .author_ids
+                        .iter()
+                        .map(|id| (ObjType::Author, *id))
+                        .chain(
+                            subcorpus
+                                .collection_ids
+                                .iter()
+                                .map(|id| (ObjType::Collection, *id)),
+                        );
+                    for (t, id) in selected {
+                        if !keys.contains(self, obj_id(id, t), &mut repairs.unlisted_pages)? {
+                            report.problems.push(Problem::MissingSubcorpusReference(
+                                subcorpus.name.clone(),
+                                t,
+                                id,
+                            ));
+                        }
+                    }
+                }
+            }
+            Err(CorpusError::DecodingError(_)) => {
+                report.problems.push(Problem::UnreadableSubcorpora)
+            }
+            Err(e) => return Err(e),
+        }
+        report.problems.extend(
+            repairs
+                .unlisted_pages
+                .iter()
+                .copied()
+                .map(Problem::UnlistedPage),
+        );
+        if let Some(raw) = self.read_reserved(ARTIFACT_DIRECTORY_ID)? {
+            let artifacts = ArtifactDirectory::from_bytes(&raw)?;
+            for (name, id) in artifacts.ids.iter() {
+                if self.read_reserved(*id)?.is_none() {
+                    report.problems.push(Problem::MissingArtifact(name.clone()));
+                    repairs.missing_artifacts.push(name.clone());
+                } else if artifacts.is_stale(name) {
+                    report.problems.push(Problem::StaleArtifact(name.clone()));
+                    repairs.stale_artifacts.push(name.clone());
+                }
+            }
+        }
+        let page_ids = directory
+            .0
+            .iter()
+            .filter(|page_id| !repairs.missing_pages.contains(page_id))
+            .chain(repairs.unlisted_pages.iter())
+            .copied()
+            .collect::<BTreeSet<u64>>()
+            .into_iter()
+            .collect::<Vec<u64>>();
+        if let Some(manifest) = self.manifest()? {
+            if manifest.allocator.is_behind(&page_ids) {
+                report.problems.push(Problem::StaleAllocator);
+                repairs.stale_allocator = true;
+            }
+        }
+        if repair {
+            report.repaired = self.repair(repairs)?;
+        }
+        Ok(report)
+    }
+    /// apply `repairs` in one batch under the write lock. the directories,
+    /// pages and manifest are read again first, so whatever was committed
+    /// since the scan is kept, and only what is still wrong is fixed.
+    /// returns the problems that were
+    fn repair(&self, repairs: Repairs) -> CorpusResult<Vec<Problem>> {
+        let format = self.page_format()?;
+        self.update_raw(|st| {
+            let db = st.db();
+            let mut batch = Vec::new();
+            let mut repaired = Vec::new();
+            let mut directory = match db.read(PAGE_DIRECTORY_ID)? {
+                Some(raw) => PageDirectory::from_bytes(&raw)?,
+                None => PageDirectory::default(),
+            };
+            let mut directory_changed = false;
+            for page_id in repairs.missing_pages {
+                if db.read(page_id)?.is_none() && directory.0.remove(&page_id) {
+                    directory_changed = true;
+                    repaired.push(Problem::MissingPage(page_id));
+                }
+            }
+            for page_id in repairs.unlisted_pages {
+                if db.read(page_id)?.is_some() && directory.0.insert(page_id) {
+                    directory_changed = true;
+                    repaired.push(Problem::UnlistedPage(page_id));
+                }
+            }
+            if directory_changed {
+                batch.push((PAGE_DIRECTORY_ID, Some(directory.to_bytes()?)));
+            }
+            let dictionary = decode_dictionary(db.read(DICTIONARY_ID)?.map(|raw| raw.to_vec()))?;
+            for (page_id, moves) in repairs.moves {
+                let Some(raw) = db.read(page_id)? else {
+                    continue;
+                };
+                let mut page = Page::from_bytes(&raw)
+                    .map_err(|_| CorpusError::DecodingError(format!("Decoding page {page_id}")))?;
+                let mut moved = false;
+                for (stored, expected) in moves {
+                    let (stored, expected) = ((page_id, stored), (page_id, expected));
+                    let misplaced = page
+                        .0
+                        .get(&stored.1)
+                        .is_some_and(|entity| entity.obj_id() == expected);
+                    if misplaced && !page.0.contains_key(&expected.1) {
+                        let entity = page.0.remove(&stored.1).expect("entity on page");
+                        page.0.insert(expected.1, entity);
+                        moved = true;
+                        repaired.push(Problem::MisplacedEntity(stored, expected));
+                    }
+                }
+                if moved {
+                    batch.push((page_id, Some(page.encode(format)?)));
+                }
+            }
+            for page_id in repairs.stale_counts {
+                let Some(raw) = db.read(page_id)? else {
+                    continue;
+                };
+                let page = Page::from_bytes(&raw)
+                    .map_err(|_| CorpusError::DecodingError(format!("Decoding page {page_id}")))?;
+                let strings = match db.read(strings_page_id(page_id))? {
+                    Some(raw) => Strings::from_bytes(&raw),
+                    None => Strings::new(),
+                }
+                .with_dictionary(dictionary.clone());
+                let counts = PageCounts::from_page(&page, &strings)?.to_bytes()?;
+                batch.push((counts_page_id(page_id), Some(counts)));
+                repaired.push(Problem::StalePageCounts(page_id));
+            }
+            if let Some(raw) = db.read(ARTIFACT_DIRECTORY_ID)? {
+                let mut artifacts = ArtifactDirectory::from_bytes(&raw)?;
+                let mut dropped = false;
+                for name in repairs.missing_artifacts {
+                    if let Some(id) = artifacts.ids.get(&name).copied() {
+                        if db.read(id)?.is_none() {
+                            artifacts.remove(&name);
+                            dropped = true;
+                            repaired.push(Problem::MissingArtifact(name));
+                        }
+                    }
+                }
+                for name in repairs.stale_artifacts {
+                    if artifacts.is_stale(&name) {
+                        if let Some(id) = artifacts.remove(&name) {
+                            batch.push((id, None));
+                            dropped = true;
+                            repaired.push(Problem::StaleArtifact(name));
+                        }
+                    }
+                }
+                if dropped {
+                    batch.push((ARTIFACT_DIRECTORY_ID, Some(artifacts.to_bytes()?)));
+                }
+            }
+            if repairs.stale_allocator {
+                let page_ids = directory.0.iter().copied().collect::<Vec<u64>>();
+                if let Some(mut manifest) = read_manifest(db)? {
+                    if manifest.allocator.is_behind(&page_ids) {
+                        manifest.allocator = Allocator::default();
+                        batch.push((MANIFEST_ID, Some(manifest.to_bytes()?)));
+                        st.allocator = Allocator::default();
+                        repaired.push(Problem::StaleAllocator);
+                    }
+                }
+            }
+            Ok((batch, repaired))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::subcorpus::Subcorpus;
+    use crate::entities::CorpusEntity;
+    use crate::marble::{_test_config, _test_document, _test_token, read_generation, CorpusWrite};
+
+    #[test]
+    fn verify_and_repair() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("verify"))?;
+        // pins the allocator to page 0 before anything is written
+        writer.allocate_ids(ObjType::Author, 1)?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.extend(_test_document(4, 2, 3).pop());
+        writer.write_objs(objs)?;
+        let manifest = writer.read_reserved(MANIFEST_ID)?;
+        writer.write_objs(
+            (1..=4)
+                .map(|i| _test_token(i, if i == 4 { 4 } else { 1 }, 2))
+                .collect::<Vec<_>>(),
+        )?;
+        // as left by a writer that didn't account for ids it was given
+        writer.write_raw(vec![(MANIFEST_ID, manifest)])?;
+        writer.write_artifact("stats", vec![1], writer.with_db(read_generation)?)?;
+        let clean = writer.verify(false)?;
+        assert_eq!((clean.pages, clean.entities), (2, 8));
+        assert_eq!(clean.problems, vec![Problem::StaleAllocator]);
+        writer.verify(true)?;
+        assert!(writer.verify(false)?.problems.is_empty());
+
+        let document = obj_id(4, ObjType::Document);
+        let mut documents = writer.stored_page(0)?.expect("page 0");
+        documents.0.remove(&document.1);
+        let bad = obj_id((1 << 64) | 1, ObjType::Token);
+        let misplaced = obj_id((1 << 64) | 3, ObjType::Token);
+        let mut tokens = writer.stored_page(1)?.expect("page 1");
+        if let Some(CorpusEntity::Token(t)) = tokens.0.get_mut(&bad.1) {
+            *t.string_refs_mut()[0] = StringRef::new(1000, 5);
+        }
+        let entity = tokens.0.remove(&misplaced.1).expect("token");
+        tokens.0.insert(misplaced.1 + 0x10, entity);
+        let mut directory = PageDirectory::from_bytes(
+            &writer.read_reserved(PAGE_DIRECTORY_ID)?.expect("directory"),
+        )?;
+        directory.0.insert(7);
+        let artifacts = ArtifactDirectory::from_bytes(
+            &writer
+                .read_reserved(ARTIFACT_DIRECTORY_ID)?
+                .expect("artifacts"),
+        )?;
+        writer.write_raw(vec![
+            (0, Some(documents.to_bytes()?)),
+            (1, Some(tokens.to_bytes()?)),
+            (PAGE_DIRECTORY_ID, Some(directory.to_bytes()?)),
+            (artifacts.ids["stats"], None),
+        ])?;
+
+        let bad_string = Problem::BadString(bad, StringRef::new(1000, 5));
+        let dangling =
+            Problem::MissingReference(obj_id((1 << 64) | 4, ObjType::Token), ObjType::Document, 4);
+        let report = writer.verify(true)?;
+        assert_eq!(
+            report.problems,
+            vec![
+                // page 0 lost a document, and the references it held
+                Problem::StalePageCounts(0),
+                bad_string.clone(),
+                Problem::MisplacedEntity((1, misplaced.1 + 0x10), misplaced),
+                Problem::MissingPage(7),
+                dangling.clone(),
+                Problem::MissingArtifact("stats".to_string()),
+            ]
+        );
+        assert!(!report.is_clean());
+        assert_eq!(writer.verify(false)?.problems, vec![bad_string, dangling]);
+        assert_eq!(writer.page_ids()?, vec![0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_counts_subcorpora_and_artifacts() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("verify-derived"))?;
+        let mut objs = _test_document(1, 2, 3);
+        objs.extend([_test_token(1, 1, 2), _test_token(2, 1, 2)]);
+        writer.write_objs(objs)?;
+        let mut subcorpus = Subcorpus::new("missing");
+        subcorpus.author_ids = vec![2, 99];
+        writer.save_subcorpus(&subcorpus)?;
+        writer.write_artifact("stats", vec![1], writer.with_db(read_generation)?)?;
+        let mut artifacts = ArtifactDirectory::from_bytes(
+            &writer
+                .read_reserved(ARTIFACT_DIRECTORY_ID)?
+                .expect("artifacts"),
+        )?;
+        // as saved by a writer that didn't check the generation
+        artifacts.since += 1;
+        writer.write_raw(vec![
+            (counts_page_id(1), Some(PageCounts::default().to_bytes()?)),
+            (ARTIFACT_DIRECTORY_ID, Some(artifacts.to_bytes()?)),
+        ])?;
+
+        let dangling =
+            Problem::MissingSubcorpusReference("missing".to_string(), ObjType::Author, 99);
+        let report = writer.verify(true)?;
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::StalePageCounts(1),
+                dangling.clone(),
+                Problem::StaleArtifact("stats".to_string()),
+            ]
+        );
+        assert_eq!(writer.verify(false)?.problems, vec![dangling]);
+        assert_eq!(writer.read_reserved(artifacts.ids["stats"])?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn repair_keeps_later_writes() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("verify-later"))?;
+        writer.write_objs(_test_document(1, 2, 3))?;
+        let generation = writer.with_db(read_generation)?;
+        // found by a scan that ran before page 0 was written
+        let repairs = Repairs {
+            missing_pages: vec![0],
+            stale_allocator: true,
+            ..Default::default()
+        };
+        assert!(writer.repair(repairs)?.is_empty());
+        assert_eq!(writer.page_ids()?, vec![0]);
+        assert_eq!(writer.with_db(read_generation)?, generation);
+        Ok(())
+    }
+}
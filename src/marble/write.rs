@@ -1,130 +1,275 @@
+use crate::config::CorpusConfig;
+use crate::entities::strings::Strings;
 use crate::entities::{CorpusEntity, HydratedEntity};
-use crate::env_default;
 use crate::errors::{CorpusError, CorpusResult};
-use crate::marble::{pf, CorpusState, CorpusWrite, Page};
+use crate::marble::manifest::{
+    check_format, read_manifest, stored_format_version, Manifest, FORMAT_VERSION, MANIFEST_ID,
+};
+use crate::marble::paging::{Allocator, PageSize, PagingPolicy};
+use crate::marble::read::ReadState;
+use crate::marble::{
+    counts_page_id, decode_dictionary, integrity::DeletePolicy, read_generation, strings_page_id,
+    ArtifactDirectory, CorpusState, CorpusWrite, Page, PageCounts, PageDirectory, PageFormat,
+    ARTIFACT_DIRECTORY_ID, DICTIONARY_ID, GENERATION_ID, PAGE_DIRECTORY_ID, RESERVED_PAGE_FLAG,
+    STRINGS_PAGE_FLAG,
+};
+use fs2::FileExt;
 use marble;
 use std::borrow::{Borrow, BorrowMut};
 use std::collections::BTreeMap;
-use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
 use std::ops::Deref;
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+
+/// advisory lock file held by the one open writer, containing its pid
+pub(crate) const WRITER_LOCK_FILE: &str = "corpus.lock";
 
 #[derive(Debug)]
-struct WriteState {
-    author_id: u64,
-    collection_id: u64,
-    document_id: u64,
-    token_id: u64,
+pub(crate) struct WriteState {
+    pub(crate) delete_policy: DeletePolicy,
+    page_format: PageFormat,
+    pub(crate) paging: PagingPolicy,
+    /// for readers opened through `reader`
+    cache_bytes: usize,
+    /// recorded in the manifest of corpora this writer creates or migrates
+    label_schemas: Vec<String>,
+    pub(crate) allocator: Allocator,
+    /// the corpus directory
+    path: PathBuf,
     db: marble::Marble,
+    /// held for as long as the writer is open
+    _lock: File,
+}
+
+impl WriteState {
+    /// the underlying store, for batches built under the write lock
+    pub(crate) fn db(&self) -> &marble::Marble {
+        &self.db
+    }
+    /// the stored page `page_id`, if there is one
+    pub(crate) fn stored_page(&self, page_id: u64) -> CorpusResult<Option<Page>> {
+        self.db
+            .read(page_id)?
+            .map(|raw| {
+                Page::from_bytes(&raw)
+                    .map_err(|_| CorpusError::DecodingError(format!("Decoding page {page_id}")))
+            })
+            .transpose()
+    }
+    pub(crate) fn page_ids(&self) -> CorpusResult<Vec<u64>> {
+        match self.db.read(PAGE_DIRECTORY_ID)? {
+            Some(raw) => Ok(PageDirectory::from_bytes(&raw)?.0.into_iter().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// the lock file of the corpus at `path`, created if need be
+pub(crate) fn lock_file(path: &Path) -> CorpusResult<File> {
+    fs::create_dir_all(path)?;
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(WRITER_LOCK_FILE))?)
 }
 
 impl CorpusState<WriteState> {
-    pub(crate) fn default_config() -> CorpusResult<marble::Config> {
-        let pth = env::var("MARBLE_PATH").ok().unwrap_or("corpus".to_string());
-        let path = PathBuf::from(pth);
-        let zstd_compression_level = match env::var("MARBLE_ZSTD_COMPRESSION_LEVEL").ok() {
-            None => None,
-            Some(v) => Some(v.parse::<i32>().map_err(|_| {
-                CorpusError::ConfigurationError("Invalid MARBLE_ZSTD_COMPRESSION_LEVEL".to_string())
-            })?),
-        };
-        let fsync_each_batch = true;
-        let target_file_size = env_default!("TARGET_FILE_SIZE", 512_000_000usize, usize);
-        let file_compaction_percent = env_default!("FILE_COMPACTION_SIZE", 20u8, u8);
-        let max_object_size = env_default!("MAX_OBJECT_SIZE", 1_024_000usize, usize);
-        let small_file_cleanup_threshold =
-            env_default!("SMALL_FILE_CLEANUP_THRESHOLD", 128usize, usize);
-        let min_compaction_files = env_default!("MIN_COMPACTION_FILES", 128usize, usize);
-        Ok(marble::Config {
-            path,
-            zstd_compression_level,
-            fsync_each_batch,
-            target_file_size,
-            file_compaction_percent,
-            max_object_size,
-            small_file_cleanup_threshold,
-            min_compaction_files,
-            partition_function: pf,
-        })
-    }
-    pub(crate) fn new(config: marble::Config) -> CorpusResult<Self> {
+    pub(crate) fn new(config: CorpusConfig) -> CorpusResult<Self> {
+        config.validate()?;
+        let lock = Self::lock_writer(&config.path)?;
         let db = config
+            .marble_config()
             .open()
             .map_err(|e| CorpusError::BackingStorageError(e))?;
+        // older corpora are opened as they are, so they can be migrated
+        let allocator = match stored_format_version(&db)? {
+            None => {
+                let manifest = Manifest::new(config.label_schemas.clone());
+                db.write_batch(vec![(MANIFEST_ID, Some(manifest.to_bytes()?))])?;
+                manifest.allocator
+            }
+            Some(v) if v < FORMAT_VERSION => Allocator::default(),
+            Some(_) => {
+                check_format(&db)?;
+                let manifest = read_manifest(&db)?
+                    .ok_or_else(|| CorpusError::DecodingError("loading manifest".to_string()))?;
+                if manifest.label_schemas != config.label_schemas {
+                    return Err(CorpusError::ConfigurationError(format!(
+                        "corpus is labelled with {:?}, not {:?}",
+                        manifest.label_schemas, config.label_schemas
+                    )));
+                }
+                manifest.allocator
+            }
+        };
         let cs = WriteState {
-            author_id: 0,
-            collection_id: 0,
-            document_id: 0,
-            token_id: 0,
+            delete_policy: DeletePolicy::default(),
+            page_format: PageFormat::default(),
+            paging: config.paging,
+            cache_bytes: config.cache_bytes,
+            label_schemas: config.label_schemas,
+            allocator,
+            path: config.path,
             db,
+            _lock: lock,
         };
         CorpusState::_new(cs)
     }
+    /// the corpus at `MARBLE_PATH`, configured by its `corpus.toml` and the
+    /// environment
     pub(crate) fn default() -> CorpusResult<Self> {
-        Self::new(Self::default_config()?)
+        Self::new(CorpusConfig::load(CorpusConfig::default_path())?)
+    }
+    /// take the advisory lock on `path` for writing. marble locks its
+    /// directory on open as well, so nothing else can open the corpus,
+    /// not even a reader, until this writer is dropped
+    fn lock_writer(path: &Path) -> CorpusResult<File> {
+        let mut lock = lock_file(path)?;
+        lock.try_lock_exclusive()
+            .map_err(|_| CorpusError::WriterLockedError(path.to_path_buf()))?;
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+        Ok(lock)
+    }
+    /// a reader over the same storage, usable while this writer is open.
+    /// marble locks its directory on every open, so this is the only way to
+    /// read alongside a writer in the same process. other processes can
+    /// read a `backup` through `ReadState::open_backup` instead
+    pub(crate) fn reader(&self) -> CorpusResult<CorpusState<ReadState>> {
+        let st = self._read_lock("Reader lock error".to_string())?;
+        CorpusState::<ReadState>::from_db(st.db.clone(), st.cache_bytes)
     }
 
-    fn author_id(&self) -> CorpusResult<u64> {
-        let st: &RwLock<WriteState> = self.lock();
+    pub(crate) fn delete_policy(&self) -> CorpusResult<DeletePolicy> {
         Ok(self
-            ._read_lock("Author id lock error".to_string())?
-            .deref()
-            .author_id)
-    }
-    fn next_author_id(&self) -> CorpusResult<u64> {
-        let mut st = self._write_lock("Next author id lock error".to_string())?;
-        let mut author_id = st.borrow_mut().author_id;
-        author_id = author_id
-            .checked_add(1)
-            .ok_or(CorpusError::IdOverflowError("Author".into()))?;
-        Ok(author_id.clone())
-    }
-    fn collection_id(&self) -> CorpusResult<u64> {
+            ._read_lock("Delete policy lock error".to_string())?
+            .delete_policy)
+    }
+    /// how deleting an entity that others still refer to is handled
+    pub(crate) fn set_delete_policy(&self, policy: DeletePolicy) -> CorpusResult<()> {
+        self._write_lock("Delete policy lock error".to_string())?
+            .delete_policy = policy;
+        Ok(())
+    }
+    pub(crate) fn page_format(&self) -> CorpusResult<PageFormat> {
         Ok(self
-            ._read_lock("Collection id lock error".to_string())?
-            .deref()
-            .collection_id)
-    }
-    fn next_collection_id(&self) -> CorpusResult<u64> {
-        let mut st = self._write_lock("Next collection id lock error".to_string())?;
-        let mut collection_id = st.borrow_mut().author_id;
-        collection_id = collection_id
-            .checked_add(1)
-            .ok_or(CorpusError::IdOverflowError("Collection".into()))?;
-        Ok(collection_id.clone())
-    }
-    fn document_id(&self) -> CorpusResult<u64> {
-        let st: &RwLock<WriteState> = self.lock();
+            ._read_lock("Page format lock error".to_string())?
+            .page_format)
+    }
+    /// how pages are laid out from now on. pages already written keep their
+    /// format until they are next written
+    pub(crate) fn set_page_format(&self, format: PageFormat) -> CorpusResult<()> {
+        self._write_lock("Page format lock error".to_string())?
+            .page_format = format;
+        Ok(())
+    }
+    pub(crate) fn label_schemas(&self) -> CorpusResult<Vec<String>> {
         Ok(self
-            ._read_lock("Document id lock error".to_string())?
-            .deref()
-            .document_id)
-    }
-    fn next_document_id(&self) -> CorpusResult<u64> {
-        let st: &RwLock<WriteState> = self.lock();
-        let mut st = st
-            .write()
-            .map_err(|_| CorpusError::LockError("Next document id lock error".to_string()))?;
-        let mut document_id = st.borrow_mut().author_id;
-        document_id = document_id
-            .checked_add(1)
-            .ok_or(CorpusError::IdOverflowError("Document".into()))?;
-        Ok(document_id.clone())
-    }
-    fn token_id(&self) -> CorpusResult<u64> {
+            ._read_lock("Label schemas lock error".to_string())?
+            .label_schemas
+            .clone())
+    }
+    /// error unless the stored data is in the current format
+    pub(crate) fn check_format(&self) -> CorpusResult<()> {
+        self.with_db(check_format)
+    }
+    /// run `f` against the underlying store
+    pub(crate) fn with_db<T>(
+        &self,
+        f: impl FnOnce(&marble::Marble) -> CorpusResult<T>,
+    ) -> CorpusResult<T> {
+        f(&self._read_lock("Storage lock error".to_string())?.db)
+    }
+    /// the directory the corpus is stored in
+    pub(crate) fn path(&self) -> CorpusResult<PathBuf> {
+        Ok(self._read_lock("Path lock error".to_string())?.path.clone())
+    }
+    pub(crate) fn page_ids(&self) -> CorpusResult<Vec<u64>> {
+        self._read_lock("Page ids lock error".to_string())?
+            .page_ids()
+    }
+    /// the stored page `page_id`, if there is one
+    pub(crate) fn stored_page(&self, page_id: u64) -> CorpusResult<Option<Page>> {
+        self._read_lock("Stored page lock error".to_string())?
+            .stored_page(page_id)
+    }
+    /// raw bytes of the reserved object `id`
+    pub(crate) fn read_reserved(&self, id: u64) -> CorpusResult<Option<Vec<u8>>> {
         Ok(self
-            ._read_lock("Token id lock error".to_string())?
-            .deref()
-            .token_id)
-    }
-    fn next_token_id(&self) -> CorpusResult<u64> {
-        let mut st = self._write_lock("Next token id lock error".to_string())?;
-        let mut token_id = st.borrow_mut().author_id;
-        token_id = token_id
-            .checked_add(1)
-            .ok_or(CorpusError::IdOverflowError("Token".into()))?;
-        Ok(token_id.clone())
+            ._read_lock("Read reserved object lock error".to_string())?
+            .borrow()
+            .db
+            .read(id)?
+            .map(|raw| raw.to_vec()))
+    }
+    /// replace (or with `None`, remove) the reserved object `id`
+    pub(crate) fn write_reserved(&self, id: u64, bytes: Option<Vec<u8>>) -> CorpusResult<()> {
+        self.write_raw(vec![(id, bytes)])
+    }
+    /// write raw objects in one atomic batch, bypassing page bookkeeping
+    pub(crate) fn write_raw(&self, batch: Vec<(u64, Option<Vec<u8>>)>) -> CorpusResult<()> {
+        self.update_raw(|_| Ok((batch, ())))
+    }
+    /// write raw objects in one atomic batch under the write lock, exactly
+    /// as given. unlike `write_raw` the generation isn't moved on, so a
+    /// batch can set it; only for filling a corpus nothing has read yet
+    pub(crate) fn load_raw(&self, batch: Vec<(u64, Option<Vec<u8>>)>) -> CorpusResult<()> {
+        self._write_lock("Load raw objects lock error".to_string())?
+            .borrow_mut()
+            .db
+            .write_batch(batch)
+            .map_err(|e| CorpusError::BackingStorageError(e))
+    }
+    /// build a batch of raw objects from what's stored and write it, all
+    /// under the write lock, so nothing can commit in between. an empty
+    /// batch commits nothing
+    pub(crate) fn update_raw<F, T>(&self, f: F) -> CorpusResult<T>
+    where
+        F: FnOnce(&mut WriteState) -> CorpusResult<(Vec<(u64, Option<Vec<u8>>)>, T)>,
+    {
+        let mut st = self._write_lock("Update raw objects lock error".to_string())?;
+        let (mut batch, out) = f(st.borrow_mut())?;
+        if batch.is_empty() {
+            return Ok(out);
+        }
+        batch.push(generation_entry(next_generation(&st.db)?));
+        st.borrow_mut()
+            .db
+            .write_batch(batch)
+            .map_err(|e| CorpusError::BackingStorageError(e))?;
+        Ok(out)
+    }
+    /// store a derived artifact under `name`, replacing any previous
+    /// version. `generation` is the one it was computed at; if entities have
+    /// been written since, it fails with `StaleSnapshotError`
+    pub(crate) fn write_artifact(
+        &self,
+        name: &str,
+        bytes: Vec<u8>,
+        generation: u64,
+    ) -> CorpusResult<()> {
+        let mut st = self._write_lock("Write artifact lock error".to_string())?;
+        let st = st.borrow_mut();
+        let mut directory = if let Some(raw) = st.db.read(ARTIFACT_DIRECTORY_ID)? {
+            ArtifactDirectory::from_bytes(&raw)?
+        } else {
+            ArtifactDirectory::default()
+        };
+        if generation < directory.since {
+            return Err(CorpusError::StaleSnapshotError(generation, directory.since));
+        }
+        let id = directory.id_for(name);
+        directory.generations.insert(name.to_string(), generation);
+        directory.saves = Some(directory.saves.map_or(1, |n| n.wrapping_add(1)));
+        st.db
+            .write_batch(vec![
+                (id, Some(bytes)),
+                (ARTIFACT_DIRECTORY_ID, Some(directory.to_bytes()?)),
+            ])
+            .map_err(|e| CorpusError::BackingStorageError(e))
     }
     fn _read_lock(&self, msg: String) -> CorpusResult<std::sync::RwLockReadGuard<'_, WriteState>> {
         self.lock().read().map_err(|_| CorpusError::LockError(msg))
@@ -137,47 +282,338 @@ impl CorpusState<WriteState> {
     }
 }
 
-impl CorpusWrite for CorpusState<WriteState> {
-    fn write_objs(&self, objs: impl AsRef<[HydratedEntity]>) -> CorpusResult<()> {
-        let objs = objs.as_ref();
-        let mut updates: BTreeMap<u64, Vec<(u64, HydratedEntity)>> = BTreeMap::new();
-        for obj in objs {
-            let (page_id, obj_id) = obj.obj_id();
-            todo!()
-            // updates
-            //     .entry(page_id)
-            //     .and_modify(|entries| entries.push((obj_id, *obj)))
-            //     .or_insert(vec![(obj_id, *obj)]);
+/// the generation the next committed write moves the corpus on to
+fn next_generation(db: &marble::Marble) -> CorpusResult<u64> {
+    read_generation(db)?
+        .checked_add(1)
+        .ok_or(CorpusError::IdOverflowError("Generation".into()))
+}
+
+/// batch entries dropping every derived artifact, since they no longer
+/// match the data, and recording that ones computed before the next
+/// generation mustn't be saved later
+pub(crate) fn drop_artifacts(db: &marble::Marble) -> CorpusResult<Vec<(u64, Option<Vec<u8>>)>> {
+    let mut batch = Vec::new();
+    if let Some(raw) = db.read(ARTIFACT_DIRECTORY_ID)? {
+        for id in ArtifactDirectory::from_bytes(&raw)?.ids.values() {
+            batch.push((*id, None));
         }
-        let batch = {
-            let mut batch: Vec<(u64, Option<Vec<u8>>)> = Vec::with_capacity(objs.len());
-            // lock will be dropped at end of block so getting the write lock later is ok
-            let s = self._read_lock("Read lock error retrieving pages for updates".to_string())?;
-            let st = s.borrow();
-            for (page_id, entries) in updates.into_iter() {
-                let page = st.db.read(page_id)?;
-                if let Some(raw) = page {
-                    let mut page = minicbor::decode::<Page>(raw.deref()).map_err(|_| {
+    }
+    let artifacts = ArtifactDirectory {
+        since: next_generation(db)?,
+        ..Default::default()
+    };
+    batch.push((ARTIFACT_DIRECTORY_ID, Some(artifacts.to_bytes()?)));
+    Ok(batch)
+}
+
+/// the batch entry recording `generation`
+fn generation_entry(generation: u64) -> (u64, Option<Vec<u8>>) {
+    (GENERATION_ID, Some(generation.to_be_bytes().to_vec()))
+}
+
+#[derive(Debug)]
+pub(crate) enum Change<'a> {
+    Write(&'a HydratedEntity),
+    Update(&'a HydratedEntity),
+    Delete,
+}
+
+impl CorpusState<WriteState> {
+    /// apply the changes `plan` comes up with (page id -> [(entity key,
+    /// change)]) in one batch. `plan` runs under the write lock, against
+    /// what's stored at the time, so whatever it checks still holds when
+    /// the changes are written. pages that had entities replaced or removed
+    /// get their strings compacted, every page written gets its counts
+    /// redone, and pages left empty are removed altogether
+    pub(crate) fn apply_changes<'a, F>(&self, plan: F) -> CorpusResult<()>
+    where
+        F: FnOnce(&WriteState) -> CorpusResult<BTreeMap<u64, Vec<(u64, Change<'a>)>>>,
+    {
+        // held from reading the pages through to writing them, so nothing
+        // can commit in between
+        let mut s = self._write_lock("Write lock error".to_string())?;
+        let (batch, allocator) = {
+            let st: &WriteState = s.borrow();
+            check_format(&st.db)?;
+            let changes = plan(st)?;
+            for page_id in changes.keys() {
+                if page_id & (STRINGS_PAGE_FLAG | RESERVED_PAGE_FLAG) != 0 {
+                    return Err(CorpusError::InvalidDataError(format!(
+                        "page id {page_id:#x} is reserved"
+                    )));
+                }
+            }
+            let mut batch: Vec<(u64, Option<Vec<u8>>)> = Vec::with_capacity(changes.len() * 2 + 1);
+            let mut directory = if let Some(raw) = st.db.read(PAGE_DIRECTORY_ID)? {
+                PageDirectory::from_bytes(&raw)?
+            } else {
+                PageDirectory::default()
+            };
+            let dictionary = decode_dictionary(st.db.read(DICTIONARY_ID)?)?;
+            let mut allocator = st.allocator.clone();
+            for (page_id, entries) in changes.into_iter() {
+                let mut before = PageSize::default();
+                let mut page = if let Some(raw) = st.db.read(page_id)? {
+                    before.page_bytes = raw.len();
+                    Page::from_bytes(raw.deref()).map_err(|_| {
                         CorpusError::DecodingError(format!("Decoding page {page_id}"))
-                    })?;
-                    for (id, obj) in entries.iter() {
-                        // page.0.insert(*id, *obj);
-                        todo!()
-                    }
-                    batch.push((page_id, Some(page.to_bytes()?)));
+                    })?
                 } else {
-                    // let entries = entries.into_iter().collect::<BTreeMap<u64, CorpusEntity>>();
-                    // batch.push((page_id, Some(Page(entries).to_bytes()?)));
-                    todo!()
+                    Page(BTreeMap::new())
                 };
+                before.entities = page.0.len();
+                let mut strings = if let Some(raw) = st.db.read(strings_page_id(page_id))? {
+                    Strings::from_bytes(&raw)
+                } else {
+                    Strings::new()
+                }
+                .with_dictionary(dictionary.clone());
+                before.strings_bytes = strings.as_bytes().len();
+                allocator.written(
+                    page_id,
+                    entries.iter().filter_map(|(id, change)| match change {
+                        Change::Delete => None,
+                        _ => Some(*id),
+                    }),
+                );
+                for string_ref in page.0.values().flat_map(|e| e.string_refs()) {
+                    strings.index(string_ref)?;
+                }
+                let mut dead_strings = false;
+                for (id, change) in entries.into_iter() {
+                    match change {
+                        Change::Write(obj) => {
+                            dead_strings |=
+                                page.0.insert(id, obj.dehydrate(&mut strings)?).is_some();
+                        }
+                        Change::Update(obj) => {
+                            if !page.0.contains_key(&id) {
+                                return Err(CorpusError::EntityNotFoundError((page_id, id)));
+                            }
+                            page.0.insert(id, obj.dehydrate(&mut strings)?);
+                            dead_strings = true;
+                        }
+                        Change::Delete => {
+                            page.0
+                                .remove(&id)
+                                .ok_or(CorpusError::EntityNotFoundError((page_id, id)))?;
+                            dead_strings = true;
+                        }
+                    }
+                }
+                if page.0.is_empty() {
+                    batch.push((page_id, None));
+                    batch.push((strings_page_id(page_id), None));
+                    batch.push((counts_page_id(page_id), None));
+                    directory.0.remove(&page_id);
+                    continue;
+                }
+                if dead_strings {
+                    strings = page.compact_strings(&strings)?;
+                }
+                let encoded = page.encode(st.page_format)?;
+                st.paging.check(
+                    page_id,
+                    before,
+                    PageSize {
+                        entities: page.0.len(),
+                        page_bytes: encoded.len(),
+                        strings_bytes: strings.as_bytes().len(),
+                    },
+                )?;
+                batch.push((page_id, Some(encoded)));
+                batch.push((strings_page_id(page_id), Some(strings.as_bytes().to_vec())));
+                batch.push((
+                    counts_page_id(page_id),
+                    Some(PageCounts::from_page(&page, &strings)?.to_bytes()?),
+                ));
+                directory.0.insert(page_id);
+            }
+            batch.push((PAGE_DIRECTORY_ID, Some(directory.to_bytes()?)));
+            if let Some(mut manifest) = read_manifest(&st.db)? {
+                manifest.allocator = allocator.clone();
+                batch.push((MANIFEST_ID, Some(manifest.to_bytes()?)));
             }
-            batch
+            batch.push(generation_entry(next_generation(&st.db)?));
+            batch.extend(drop_artifacts(&st.db)?);
+            (batch, allocator)
         };
-        let mut st = self._write_lock("Write lock error".to_string())?;
-        st.borrow_mut()
-            .db
+        let st: &mut WriteState = s.borrow_mut();
+        st.db
             .write_batch(batch)
             .map_err(|e| CorpusError::BackingStorageError(e))?;
+        st.allocator = allocator;
+        Ok(())
+    }
+}
+
+impl CorpusWrite for CorpusState<WriteState> {
+    fn write_objs(&self, objs: impl AsRef<[HydratedEntity]>) -> CorpusResult<()> {
+        let mut tx = self.transaction();
+        tx.write_objs(objs);
+        tx.commit()
+    }
+    fn update_objs(&self, objs: impl AsRef<[HydratedEntity]>) -> CorpusResult<()> {
+        let mut tx = self.transaction();
+        tx.update_objs(objs);
+        tx.commit()
+    }
+    fn delete_objs(&self, obj_ids: impl AsRef<[(u64, u64)]>) -> CorpusResult<()> {
+        let mut tx = self.transaction();
+        tx.delete_objs(obj_ids);
+        tx.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::author::HydratedAuthor;
+    use crate::entities::token::{HydratedToken, TOKEN_RECORD_SIZE};
+    use crate::entities::{obj_id, ObjType};
+    use crate::marble::{
+        _test_author, _test_config, _test_document, CorpusHydrate, CorpusRead, TOKEN_RECORDS_PAGE,
+    };
+
+    #[test]
+    fn single_writer() -> CorpusResult<()> {
+        let config = _test_config("single-writer");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        match CorpusState::<WriteState>::new(config.clone()) {
+            Err(CorpusError::WriterLockedError(path)) => assert_eq!(path, config.path),
+            r => panic!("opened a second writer: {r:?}"),
+        }
+        writer.write_objs(vec![_test_author(1, "Mary")])?;
+        let reader = writer.reader()?;
+        assert_eq!(reader.page_ids()?, vec![0]);
+        drop(reader);
+        drop(writer);
+        CorpusState::<WriteState>::new(config)?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_config() {
+        let mut config = _test_config("invalid-config");
+        config.paging.max_bytes = config.marble.max_object_size + 1;
+        match CorpusState::<WriteState>::new(config.clone()) {
+            Err(CorpusError::ConfigurationError(_)) => (),
+            r => panic!("opened with an invalid config: {:?}", r.map(|_| ())),
+        }
+        match CorpusState::<ReadState>::new(config) {
+            Err(CorpusError::ConfigurationError(_)) => (),
+            r => panic!("opened with an invalid config: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn update_and_delete_objs() -> CorpusResult<()> {
+        let config = _test_config("update-delete");
+        let writer = CorpusState::<WriteState>::new(config.clone())?;
+        writer.write_objs(vec![_test_author(1, "Mary"), _test_author(2, "John")])?;
+        writer.write_objs(vec![_test_author((1 << 64) | 3, "Jane")])?;
+        writer.update_objs(vec![_test_author(1, "Marianne")])?;
+        writer.delete_objs(vec![obj_id(2, ObjType::Author)])?;
+        writer.delete_objs(vec![obj_id((1 << 64) | 3, ObjType::Author)])?;
+        match writer.update_objs(vec![_test_author(2, "Johnny")]) {
+            Err(CorpusError::EntityNotFoundError(_)) => (),
+            r => panic!("updated a deleted entity: {r:?}"),
+        }
+        match writer.delete_objs(vec![obj_id(2, ObjType::Author)]) {
+            Err(CorpusError::EntityNotFoundError(_)) => (),
+            r => panic!("deleted a deleted entity: {r:?}"),
+        }
+        drop(writer);
+        let reader = CorpusState::<ReadState>::new(config)?;
+        assert_eq!(reader.page_ids()?, vec![0]);
+        assert_eq!(
+            reader.read_reserved(strings_page_id(0))?,
+            Some(b"nMarianne".to_vec())
+        );
+        assert!(reader.read_reserved(strings_page_id(1))?.is_none());
+        let mary = reader.read_obj(1u128.to_be_bytes())?;
+        match reader.hydrate_obj(&mary)? {
+            HydratedEntity::Author(a) => assert_eq!(a.name(), "Marianne"),
+            e => panic!("wrong entity {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn empty_strings_round_trip() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("empty-strings"))?;
+        writer.write_objs(vec![_test_author(1, ""), _test_author(2, "John")])?;
+        writer.write_objs(vec![HydratedEntity::Author(HydratedAuthor::new(
+            3,
+            "Jane".into(),
+            String::new(),
+        ))])?;
+        let reader = writer.reader()?;
+        for (id, name, notes) in [(1, "", "n"), (2, "John", "n"), (3, "Jane", "")] {
+            match reader.hydrate_obj(&reader.read_obj((id as u128).to_be_bytes())?)? {
+                HydratedEntity::Author(a) => assert_eq!((a.name(), a.notes()), (name, notes)),
+                e => panic!("wrong entity {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn token_record_pages() -> CorpusResult<()> {
+        let writer = CorpusState::<WriteState>::new(_test_config("token-records"))?;
+        let token = |id: u128, text: &str| {
+            HydratedEntity::Token(HydratedToken::new(
+                (1 << 64) | id,
+                1,
+                2,
+                0,
+                id as u64,
+                text.to_string(),
+                0,
+            ))
+        };
+        writer.write_objs(_test_document(1, 2, 3))?;
+        writer.write_objs(vec![token(1, "old")])?;
+        assert_ne!(
+            writer.read_reserved(1)?.expect("token page")[0],
+            TOKEN_RECORDS_PAGE
+        );
+        writer.set_page_format(PageFormat::TokenRecords)?;
+        writer.write_objs(vec![token(2, "new")])?;
+        let raw = writer.read_reserved(1)?.expect("token page");
+        assert_eq!(raw[0], TOKEN_RECORDS_PAGE);
+        assert_eq!(raw.len(), 1 + 2 * TOKEN_RECORD_SIZE);
+        // the page of authors, collections and documents stays CBOR
+        assert_ne!(
+            writer.read_reserved(0)?.expect("page")[0],
+            TOKEN_RECORDS_PAGE
+        );
+        let reader = writer.reader()?;
+        for (id, text) in [(1, "old"), (2, "new")] {
+            let (h, l) = obj_id((1 << 64) | id, ObjType::Token);
+            let entity = reader.read_obj((((h as u128) << 64) | l as u128).to_be_bytes())?;
+            match reader.hydrate_obj(&entity)? {
+                HydratedEntity::Token(t) => assert_eq!(t.text(), text),
+                e => panic!("wrong entity {e:?}"),
+            }
+        }
+        let texts = reader
+            .with_tokens(1, |records, strings| {
+                records
+                    .iter()
+                    .map(|r| strings.get_string(&r.text()))
+                    .collect::<CorpusResult<Vec<String>>>()
+            })?
+            .expect("tokens");
+        assert_eq!(texts, vec!["old", "new"]);
+        writer.set_page_format(PageFormat::Cbor)?;
+        writer.update_objs(vec![token(2, "newer")])?;
+        assert_ne!(
+            writer.read_reserved(1)?.expect("token page")[0],
+            TOKEN_RECORDS_PAGE
+        );
+        assert_eq!(writer.stored_page(1)?.expect("token page").0.len(), 2);
         Ok(())
     }
 }